@@ -1,92 +1,1631 @@
-use std::collections::BTreeMap;
-use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::Duration;
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError, TryLockError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
 use serialport::{available_ports, SerialPortInfo, SerialPortType};
 use my_device::serial_device::SerialDevice;
 
+use crate::error::ManagerError;
+#[cfg(feature = "hotplug")]
+use crate::hotplug;
+
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const DEFAULT_OPEN_BACKOFF: Duration = Duration::from_secs(5);
+const LOCK_RETRY_INTERVAL: Duration = Duration::from_millis(5);
+const MAX_CONCURRENT_OPENS: usize = 4;
+const POLLER_THREAD_NAME: &str = "serial-device-poller";
+
+///How devices are identified in the manager's map.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyStrategy {
+    ///OS port name i.e. COM15, which can change across reconnects on Windows.
+    PortName,
+    ///USB serial number, falling back to the port name if the port doesn't report one.
+    UsbSerial,
+}
+
+///Options for a SerialDeviceManager.
+#[derive(Debug, Clone)]
+pub struct ManagerConfig {
+    ///With the `hotplug` feature, ports are also re-checked as soon as the OS reports a
+    ///device node coming or going, so this can be much longer.
+    pub poll_interval: Duration,
+    pub key_by: KeyStrategy,
+    ///How long to wait before retrying a port that failed to open, i.e. claimed by another app.
+    pub open_backoff: Duration,
+    ///Log every enumerated port with its VID/PID and whether it matched, i.e. to spot
+    ///firmware that shipped with the wrong USB descriptor.
+    pub debug: bool,
+    ///Stop opening matching ports once this many devices are in the map, i.e. so a test
+    ///harness doesn't exhaust COM ports. Ports over the limit are opened as slots free up.
+    pub max_devices: Option<usize>,
+}
+
+impl Default for ManagerConfig {
+    fn default() -> Self {
+        ManagerConfig {
+            poll_interval: DEFAULT_POLL_INTERVAL,
+            key_by: KeyStrategy::PortName,
+            open_backoff: DEFAULT_OPEN_BACKOFF,
+            debug: false,
+            max_devices: None,
+        }
+    }
+}
+
+///Change in the set of managed devices, identified by device key. A device that comes
+///back on a new port name is reported as Disconnected then Connected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceEvent {
+    Connected(String),
+    Disconnected(String),
+}
+
+///Pull-based record of a device being added to or removed from the map.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceChange {
+    Added(String),
+    Removed(String),
+}
+
+///Where a matching port stands with the manager, see `SerialDeviceManager::port_status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortState {
+    ///Opened and in the device map.
+    Open,
+    ///Taken out with `remove_device`, ignored until `unremove_device`.
+    Removed,
+    ///Failed to open, i.e. claimed by another app, and waiting out its backoff.
+    Failed,
+    ///Enumerated but not opened yet, i.e. waiting for a slot under `max_devices`.
+    Available,
+}
+
+///Health check the polling thread runs on every device, for firmware that hangs while
+///its port stays enumerated. See `SerialDeviceManager::set_keep_alive`.
+pub struct KeepAlive<D> {
+    ///Time between checks, rounded up to the next poll.
+    pub interval: Duration,
+    ///Checks in a row a device can fail before it's dropped.
+    pub max_failures: u32,
+    ///Sends a lightweight command i.e. a SER_NUMBER read, returning whether the device answered.
+    pub check: Box<dyn Fn(&mut D) -> bool + Send>,
+}
+
+//A KeepAlive along with when it last ran and each device's failures in a row
+struct KeepAliveState<D> {
+    keep_alive: KeepAlive<D>,
+    last_run: Option<Instant>,
+    failures: HashMap<String, u32>,
+}
+
+//Where set_keep_alive leaves its KeepAlive. The poller takes `state` out while the checks
+//run, and only puts it back if `generation` shows it wasn't replaced or cleared meanwhile.
+struct KeepAliveSlot<D> {
+    state: Option<KeepAliveState<D>>,
+    generation: u64,
+}
+
 ///Used to keep track of multiple USB connected serial devices
 pub struct SerialDeviceManager<T>
 where
     T: SerialDevice + 'static,
 {
-    devices: Arc<Mutex<BTreeMap<String, Arc<Mutex<T::Device>>>>>,
-    removed: BTreeMap<String, bool>,
+    devices: Arc<Mutex<BTreeMap<String, DeviceEntry<T::Device>>>>,
+    removed: Arc<Mutex<BTreeMap<String, bool>>>,
+    stop: Arc<AtomicBool>,
+    poll_interval: Arc<Mutex<Duration>>,
+    subscribers: Arc<Mutex<Vec<Sender<DeviceEvent>>>>,
+    device_added: Arc<Condvar>, //paired with the devices mutex
+    changes: Arc<Mutex<Vec<DeviceChange>>>, //queued until drain_changes
+    failed: Arc<Mutex<HashMap<String, FailedOpen>>>, //keyed by port name
+    seen: Arc<Mutex<HashSet<String>>>, //every key ever opened, to tell reconnects apart
+    on_reconnect: Arc<Mutex<Option<ReconnectHook<T::Device>>>>,
+    keep_alive: Arc<Mutex<KeepAliveSlot<T::Device>>>,
+    open_backoff: Duration,
+    key_by: KeyStrategy,
+    debug: bool,
+    max_devices: Option<usize>,
+    pending: Arc<Mutex<Vec<String>>>, //port names waiting for a slot under max_devices
+    wake: Sender<()>, //cuts the polling thread's wait short
+    #[cfg(feature = "hotplug")]
+    _watcher: Option<hotplug::Watcher>, //None where notifications aren't supported
+    ports: PortSource,
+    poller: Option<JoinHandle<()>>,
+}
+
+///Callback for `SerialDeviceManager::on_reconnect`, given the device key and the freshly
+///opened device.
+pub type ReconnectHook<D> = Box<dyn Fn(&str, &mut D) + Send>;
+
+//A matching port that couldn't be opened, skipped until its backoff expires
+struct FailedOpen {
+    last_attempt: Instant,
+    attempts: u32,
+}
+
+//Outcome of an open run on a worker thread, merged into the map by the next poll
+struct OpenedPort<D> {
+    key: String,
+    port_name: String,
+    result: Result<(Mutex<D>, Option<String>), String>,
+}
+
+//Map value, remembers which port a keyed device was opened on
+struct DeviceEntry<D> {
+    port_name: String,
+    serial: Option<String>, //read once on connect with SerialDevice::read_serial
+    device: Arc<Mutex<D>>,
 }
 
+//Enumerates the ports the polling thread checks, serialport::available_ports outside tests
+type PortSource = Arc<dyn Fn() -> serialport::Result<Vec<SerialPortInfo>> + Send + Sync>;
+
 impl<T> SerialDeviceManager<T>
 where
     T: SerialDevice + 'static,
 {
     ///Create new DeviceManager will start a polling thread. Device must implement
     pub fn new() -> Self {
+        Self::with_poll_interval(DEFAULT_POLL_INTERVAL)
+    }
+
+    ///Create a new DeviceManager that checks for port changes every `interval`.
+    pub fn with_poll_interval(interval: Duration) -> Self {
+        Self::with_config(ManagerConfig {
+            poll_interval: interval,
+            ..ManagerConfig::default()
+        })
+    }
+
+    ///Create a new DeviceManager that holds at most `max` devices.
+    pub fn with_max_devices(max: usize) -> Self {
+        Self::with_config(ManagerConfig {
+            max_devices: Some(max),
+            ..ManagerConfig::default()
+        })
+    }
+
+    ///Create a new DeviceManager with the given options.
+    pub fn with_config(config: ManagerConfig) -> Self {
+        Self::with_port_source(config, Arc::new(available_ports))
+    }
+
+    fn with_port_source(config: ManagerConfig, ports: PortSource) -> Self {
         let devices = Arc::new(Mutex::new(BTreeMap::new()));
-        let removed = BTreeMap::new();
-        let manager = SerialDeviceManager { devices, removed };
-        manager.start_polling_thread();
+        let removed = Arc::new(Mutex::new(BTreeMap::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (wake, wake_receiver) = mpsc::channel();
+        let mut manager = SerialDeviceManager {
+            devices,
+            removed,
+            stop,
+            poll_interval: Arc::new(Mutex::new(config.poll_interval)),
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+            device_added: Arc::new(Condvar::new()),
+            changes: Arc::new(Mutex::new(Vec::new())),
+            failed: Arc::new(Mutex::new(HashMap::new())),
+            seen: Arc::new(Mutex::new(HashSet::new())),
+            on_reconnect: Arc::new(Mutex::new(None)),
+            keep_alive: Arc::new(Mutex::new(KeepAliveSlot {
+                state: None,
+                generation: 0,
+            })),
+            open_backoff: config.open_backoff,
+            key_by: config.key_by,
+            debug: config.debug,
+            max_devices: config.max_devices,
+            pending: Arc::new(Mutex::new(Vec::new())),
+            #[cfg(feature = "hotplug")]
+            _watcher: hotplug::watch(wake.clone()),
+            wake,
+            ports,
+            poller: None,
+        };
+        manager.poller = Some(manager.start_polling_thread(wake_receiver));
         manager
     }
 
     ///Continuously updates list of connected devices.
-    fn start_polling_thread(&self) {
+    fn start_polling_thread(&self, wake: Receiver<()>) -> JoinHandle<()> {
         let devices = self.devices.clone();
         let removed = self.removed.clone();
-        thread::spawn(move || loop {
-            let available_ports = available_ports().expect("Failed to enumerate serial ports");
-            let mut devices = devices.lock().unwrap();
-            let matching_ports = available_ports
-                .into_iter()
-                .filter(|info| match &info.port_type {
-                    SerialPortType::UsbPort(val) => val.vid == T::VID && val.pid == T::PID,
-                    _ => false,
-                })
-                .collect::<Vec<SerialPortInfo>>();
-
-            let mut changed_devices = Vec::new();
-
-            for (port_name, _) in &*devices {
-                if !matching_ports
-                    .iter()
-                    .any(|info| info.port_name == *port_name)
-                {
-                    if !removed.contains_key(port_name) {
-                        changed_devices.push(port_name.clone());
+        let stop = self.stop.clone();
+        let poll_interval = self.poll_interval.clone();
+        let subscribers = self.subscribers.clone();
+        let device_added = self.device_added.clone();
+        let changes = self.changes.clone();
+        let failed = self.failed.clone();
+        let seen = self.seen.clone();
+        let on_reconnect = self.on_reconnect.clone();
+        let keep_alive = self.keep_alive.clone();
+        let open_backoff = self.open_backoff;
+        let key_by = self.key_by;
+        let ports = self.ports.clone();
+        let debug = self.debug;
+        let max_devices = self.max_devices.unwrap_or(usize::MAX);
+        let pending = self.pending.clone();
+        let waker = self.wake.clone();
+        let opening = Mutex::new(HashSet::new()); //port names with an open in progress
+        let (open_sender, open_results) = mpsc::channel::<OpenedPort<T::Device>>();
+        let poller = thread::Builder::new().name(String::from(POLLER_THREAD_NAME));
+        let spawned = poller.spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                //a panic only costs this poll, the locks it poisons are recovered by `lock`
+                let poll = panic::catch_unwind(AssertUnwindSafe(|| {
+                    let available_ports = match ports() {
+                        Ok(ports) => ports,
+                        Err(e) => {
+                            //often transient on Windows while a driver loads, try again next poll
+                            eprintln!("Failed to enumerate serial ports: {}", e);
+                            return;
+                        }
+                    };
+                    let mut devices = lock(&devices);
+                    let removed = lock(&removed);
+                    let mut failed = lock(&failed);
+                    let mut seen = lock(&seen);
+                    let mut changed_devices = Vec::new();
+
+                    //merge opens the workers finished since the last poll before diffing, or a
+                    //device that just opened would look new and be opened a second time
+                    let mut opening = lock(&opening);
+                    while let Ok(opened) = open_results.try_recv() {
+                        opening.remove(&opened.port_name);
+                        match opened.result {
+                            //removed while it was being opened
+                            Ok(_) if removed.contains_key(&opened.key) => {}
+                            Ok((device, serial)) => {
+                                failed.remove(&opened.port_name);
+                                seen.insert(opened.key.clone());
+                                let entry = DeviceEntry {
+                                    port_name: opened.port_name,
+                                    serial,
+                                    device: Arc::new(device),
+                                };
+                                //a device that moved to a new port name replaces its old handle,
+                                //listeners see it leave first so connects and disconnects pair up
+                                if devices.insert(opened.key.clone(), entry).is_some() {
+                                    changed_devices.push(DeviceEvent::Disconnected(opened.key.clone()));
+                                }
+                                changed_devices.push(DeviceEvent::Connected(opened.key));
+                            }
+                            Err(e) => {
+                                let failure = failed
+                                    .entry(opened.port_name.clone())
+                                    .or_insert(FailedOpen {
+                                        last_attempt: Instant::now(),
+                                        attempts: 0,
+                                    });
+                                failure.last_attempt = Instant::now();
+                                failure.attempts += 1;
+                                //only the first failure is logged, retries happen once per backoff
+                                if failure.attempts == 1 {
+                                    eprintln!(
+                                        "Failed to open {}: {}, retrying every {:?}",
+                                        opened.port_name, e, open_backoff
+                                    );
+                                }
+                            }
+                        }
+                    }
+
+                    if debug {
+                        Self::log_ports(&available_ports);
+                    }
+                    let matching_ports = Self::filter_ports(available_ports);
+
+                    //forget ports that have gone away so the known-bad set stays small
+                    failed.retain(|port_name, _| {
+                        matching_ports
+                            .iter()
+                            .any(|info| info.port_name == *port_name)
+                    });
+                    let matching_ports: Vec<SerialPortInfo> = matching_ports
+                        .into_iter()
+                        .filter(|info| {
+                            failed
+                                .get(&info.port_name)
+                                .is_none_or(|failure| failure.last_attempt.elapsed() >= open_backoff)
+                        })
+                        .collect();
+
+                    //first port wins if two report the same key
+                    let mut keyed_ports = BTreeMap::new();
+                    for port_info in &matching_ports {
+                        keyed_ports
+                            .entry(device_key(key_by, port_info))
+                            .or_insert(port_info);
+                    }
+
+                    let diff = diff_ports(&devices, &keyed_ports, &removed);
+
+                    for key in diff.removed {
+                        devices.remove(&key);
+                        changed_devices.push(DeviceEvent::Disconnected(key));
+                    }
+
+                    //a driver that stalls in open only ties up its own worker, ports left over
+                    //once every worker is busy wait for a later poll
+                    let mut over_limit = Vec::new();
+                    for (key, port_info) in diff.added {
+                        //already holds a slot while its open is in progress, so it isn't pending
+                        if opening.contains(&port_info.port_name) {
+                            continue;
+                        }
+                        //a known device on a new port name replaces its entry, so it takes no new slot
+                        let at_limit = devices.len() + opening.len() >= max_devices;
+                        if at_limit && !devices.contains_key(&key) {
+                            over_limit.push(port_info.port_name.clone());
+                            continue;
+                        }
+                        if opening.len() >= MAX_CONCURRENT_OPENS {
+                            break;
+                        }
+                        opening.insert(port_info.port_name.clone());
+                        let port_name = port_info.port_name.clone();
+                        let results = open_sender.clone();
+                        let waker = waker.clone();
+                        let reconnect = seen.contains(&key);
+                        let on_reconnect = on_reconnect.clone();
+                        thread::spawn(move || {
+                            //the serial is read here too so a slow device doesn't hold up the poll
+                            let open = || {
+                                let mut device = T::open(&port_name).map_err(|e| e.to_string())?;
+                                let serial = T::read_serial(lock_mut(&mut device));
+                                //runs before the device is in the map, so nothing else can use it
+                                //yet, and out here a slow hook holds none of the manager's locks
+                                if reconnect {
+                                    if let Some(hook) = &*lock(&on_reconnect) {
+                                        hook(&key, lock_mut(&mut device));
+                                    }
+                                }
+                                Ok((device, serial))
+                            };
+                            let result = match panic::catch_unwind(open) {
+                                Ok(result) => result,
+                                Err(panic) => Err(format!("open panicked: {}", panic_message(&*panic))),
+                            };
+                            let _ = results.send(OpenedPort {
+                                key,
+                                port_name,
+                                result,
+                            });
+                            let _ = waker.send(());
+                        });
+                    }
+
+                    drop(opening);
+                    *lock(&pending) = over_limit;
+                    //release the map before notifying and sleeping so waiters and accessors aren't blocked
+                    drop(seen);
+                    drop(failed);
+                    drop(removed);
+                    drop(devices);
+                    if changed_devices
+                        .iter()
+                        .any(|event| matches!(event, DeviceEvent::Connected(_)))
+                    {
+                        device_added.notify_all();
                     }
+
+                    if !changed_devices.is_empty() {
+                        publish(&subscribers, &changes, &changed_devices);
+                    }
+                }));
+                if let Err(panic) = poll {
+                    eprintln!("Device poll panicked: {}", panic_message(&*panic));
                 }
+
+                //checks can take a while, set_keep_alive and clear_keep_alive don't wait on them
+                let taken = {
+                    let mut slot = lock(&keep_alive);
+                    let generation = slot.generation;
+                    slot.state.take().map(|state| (state, generation))
+                };
+                if let Some((mut state, generation)) = taken {
+                    let checked = panic::catch_unwind(AssertUnwindSafe(|| {
+                        evict_unresponsive(&mut state, &devices, &failed)
+                    }));
+                    match checked {
+                        Ok(evicted) if !evicted.is_empty() => publish(&subscribers, &changes, &evicted),
+                        Ok(_) => {}
+                        Err(panic) => eprintln!("Keep-alive check panicked: {}", panic_message(&*panic)),
+                    }
+                    let mut slot = lock(&keep_alive);
+                    if slot.generation == generation {
+                        slot.state = Some(state);
+                    }
+                }
+
+                //woken early by a hotplug notification or shutdown, otherwise polls on the interval
+                let interval = *lock(&poll_interval);
+                if let Err(RecvTimeoutError::Disconnected) = wake.recv_timeout(interval) {
+                    thread::sleep(interval);
+                }
+                //a burst of wakes, i.e. one hotplug event per device node, only needs this one poll
+                while wake.try_recv().is_ok() {}
             }
+            eprintln!("Device polling thread exiting");
+        });
+        spawned.expect("failed to spawn the device polling thread")
+    }
+
+    ///List ports matching the device's VID/PID without opening them.
+    pub fn scan() -> Vec<SerialPortInfo> {
+        match available_ports() {
+            Ok(ports) => Self::filter_ports(ports),
+            Err(_) => Vec::new(),
+        }
+    }
 
-            for key in &changed_devices {
-                devices.remove(&String::from(key));
+    //Shared by scan and the polling thread so both agree on what counts as a device
+    fn filter_ports(ports: Vec<SerialPortInfo>) -> Vec<SerialPortInfo> {
+        ports.into_iter().filter(Self::is_match).collect()
+    }
+
+    //VID/PID match against any of the device's USB ids
+    fn is_match(info: &SerialPortInfo) -> bool {
+        match &info.port_type {
+            SerialPortType::UsbPort(val) => T::usb_ids()
+                .iter()
+                .any(|&(vid, pid)| val.vid == vid && val.pid == pid),
+            _ => false,
+        }
+    }
+
+    //One line per enumerated port with the ids it reported, for ManagerConfig::debug
+    fn log_ports(ports: &[SerialPortInfo]) {
+        for info in ports {
+            eprintln!("{}", Self::describe_port(info));
+        }
+    }
+
+    fn describe_port(info: &SerialPortInfo) -> String {
+        let matched = if Self::is_match(info) { "matched" } else { "skipped" };
+        match &info.port_type {
+            SerialPortType::UsbPort(usb) => {
+                format!("{}: USB {:04X}:{:04X} {}", info.port_name, usb.vid, usb.pid, matched)
             }
+            other => format!("{}: {:?} {}", info.port_name, other, matched),
+        }
+    }
+
+    ///Get a channel that receives an event whenever a device is connected or disconnected.
+    pub fn subscribe(&self) -> Receiver<DeviceEvent> {
+        let (sender, receiver) = mpsc::channel();
+        lock(&self.subscribers).push(sender);
+        receiver
+    }
 
-            for port_info in &matching_ports {
-                if !devices.contains_key(&port_info.port_name)
-                    && !removed.contains_key(&port_info.port_name)
-                {
-                    let device = Arc::new(T::open(&port_info.port_name));
-                    devices.insert(port_info.port_name.clone(), device);
-                    changed_devices.push(port_info.port_name.clone());
+    ///Take every change recorded since the last call, oldest first.
+    pub fn drain_changes(&self) -> Vec<DeviceChange> {
+        std::mem::take(&mut *lock(&self.changes))
+    }
+
+    ///Get the time between port checks.
+    pub fn poll_interval(&self) -> Duration {
+        *lock(&self.poll_interval)
+    }
+
+    ///Change the time between port checks, takes effect on the next poll.
+    pub fn set_poll_interval(&self, interval: Duration) {
+        *lock(&self.poll_interval) = interval;
+    }
+
+    ///Signal the polling thread to stop. Devices already found stay in the map.
+    pub fn shutdown(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+        let _ = self.wake.send(());
+    }
+
+    ///Port names that matched but weren't opened because the manager is at `max_devices`.
+    pub fn pending_ports(&self) -> Vec<String> {
+        lock(&self.pending).clone()
+    }
+
+    ///Get the keys of attached devices, port names unless keyed by USB serial.
+    pub fn get_devices(&self) -> Vec<String> {
+        lock(&self.devices).keys().cloned().collect()
+    }
+
+    ///Number of attached devices.
+    pub fn device_count(&self) -> usize {
+        lock(&self.devices).len()
+    }
+
+    ///Whether a device with the given key is attached.
+    pub fn is_connected(&self, key: &str) -> bool {
+        lock(&self.devices).contains_key(key)
+    }
+
+    ///Block until at least one device is present or `timeout` elapses, returning the first device.
+    pub fn wait_for_device(&self, timeout: Duration) -> Option<Arc<Mutex<T::Device>>> {
+        let devices = lock(&self.devices);
+        let (devices, _) = self
+            .device_added
+            .wait_timeout_while(devices, timeout, |devices| devices.is_empty())
+            .unwrap_or_else(PoisonError::into_inner);
+        devices.values().next().map(|entry| entry.device.clone())
+    }
+
+    ///Block until a device whose key (port name, or serial with `KeyStrategy::UsbSerial`) matches
+    ///`predicate` is present or `timeout` elapses.
+    pub fn wait_for(
+        &self,
+        predicate: impl Fn(&str) -> bool,
+        timeout: Duration,
+    ) -> Option<Arc<Mutex<T::Device>>> {
+        let devices = lock(&self.devices);
+        let (devices, _) = self
+            .device_added
+            .wait_timeout_while(devices, timeout, |devices| {
+                !devices.keys().any(|key| predicate(key))
+            })
+            .unwrap_or_else(PoisonError::into_inner);
+        devices
+            .iter()
+            .find(|(key, _)| predicate(key))
+            .map(|(_, entry)| entry.device.clone())
+    }
+
+    ///Get a single device by its key.
+    pub fn get_device(&self, key: &str) -> Option<Arc<Mutex<T::Device>>> {
+        lock(&self.devices)
+            .get(key)
+            .map(|entry| entry.device.clone())
+    }
+
+    ///Serial number the device reported when it connected, to tell apart identical units
+    ///keyed by port name. None if the device isn't attached or didn't report one.
+    pub fn get_device_serial(&self, key: &str) -> Option<String> {
+        lock(&self.devices)
+            .get(key)
+            .and_then(|entry| entry.serial.clone())
+    }
+
+    ///Run `f` with the device locked, giving up with `ManagerError::Busy` if another holder
+    ///keeps it locked (i.e. during a long read) for longer than `timeout`.
+    pub fn with_device<F, R>(&self, key: &str, timeout: Duration, f: F) -> Result<R, ManagerError>
+    where
+        F: FnOnce(&mut T::Device) -> R,
+    {
+        let device = self
+            .get_device(key)
+            .ok_or_else(|| ManagerError::NotFound(String::from(key)))?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            match device.try_lock() {
+                Ok(mut device) => return Ok(f(&mut device)),
+                Err(TryLockError::Poisoned(poisoned)) => return Ok(f(&mut poisoned.into_inner())),
+                Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+                    thread::sleep(LOCK_RETRY_INTERVAL)
                 }
+                Err(TryLockError::WouldBlock) => return Err(ManagerError::Busy(String::from(key))),
             }
+        }
+    }
 
-            thread::sleep(Duration::from_millis(100));
+    ///Run `f` on the first device in the map without cloning its Arc. The map stays locked
+    ///while `f` runs, so keep it short; the poller waits on it.
+    pub fn with_first_device<F, R>(&self, f: F) -> Option<R>
+    where
+        F: FnOnce(&mut T::Device) -> R,
+    {
+        let devices = lock(&self.devices);
+        let entry = devices.values().next()?;
+        let mut device = lock(&entry.device);
+        Some(f(&mut device))
+    }
+
+    ///Register `f` to run on a device the polling thread re-opens after it disconnected,
+    ///i.e. to re-read its serial number. Brand-new devices don't trigger it. It runs on the
+    ///worker thread that opened the device, so a slow hook doesn't hold up polling.
+    pub fn on_reconnect(&self, f: ReconnectHook<T::Device>) {
+        *lock(&self.on_reconnect) = Some(f);
+    }
+
+    ///Periodically run `keep_alive.check` on each device from the polling thread.
+    ///A device that fails `max_failures` checks in a row is dropped with a Disconnected
+    ///event, and its port is retried after the open backoff like a port that failed to open.
+    pub fn set_keep_alive(&self, keep_alive: KeepAlive<T::Device>) {
+        let mut slot = lock(&self.keep_alive);
+        slot.state = Some(KeepAliveState {
+            keep_alive,
+            last_run: None,
+            failures: HashMap::new(),
         });
+        slot.generation += 1;
     }
 
-    ///Get a list of attached devices.
-    pub fn get_devices(&self) -> Vec<String> {
-        self.devices.lock().unwrap().keys().cloned().collect()
+    pub fn clear_keep_alive(&self) {
+        let mut slot = lock(&self.keep_alive);
+        slot.state = None;
+        slot.generation += 1;
+    }
+
+    ///Run `f` on every device in turn, each locked while `f` runs. The map stays locked
+    ///throughout. Returns the errors from devices that failed, keyed by device key.
+    pub fn for_each<F, E>(&self, mut f: F) -> Vec<(String, E)>
+    where
+        F: FnMut(&str, &mut T::Device) -> Result<(), E>,
+    {
+        let devices = lock(&self.devices);
+        let mut errors = Vec::new();
+        for (key, entry) in devices.iter() {
+            if let Err(e) = f(key, &mut lock(&entry.device)) {
+                errors.push((key.clone(), e));
+            }
+        }
+        errors
+    }
+
+    pub fn remove_device(&mut self, key: &str) -> bool {
+        let existed = lock(&self.devices).remove(key).is_some();
+        lock(&self.removed).insert(String::from(key), true);
+        if existed {
+            let event = DeviceEvent::Disconnected(String::from(key));
+            publish(&self.subscribers, &self.changes, &[event]);
+        }
+        true
+    }
+
+    ///Take a device off the removed list so the next poll can pick it up again.
+    pub fn unremove_device(&mut self, key: &str) {
+        lock(&self.removed).remove(key);
+    }
+
+    ///State of every matching port along with open and removed devices, by device key.
+    ///Enumerates ports, so it costs about as much as `scan`.
+    pub fn port_status(&self) -> Vec<(String, PortState)> {
+        //enumerating can be slow, so do it before taking locks the poller needs
+        let ports = (self.ports)().map(Self::filter_ports).unwrap_or_default();
+        let devices = lock(&self.devices);
+        let removed = lock(&self.removed);
+        let failed = lock(&self.failed);
+        let mut status: BTreeMap<String, PortState> = devices
+            .keys()
+            .map(|key| (key.clone(), PortState::Open))
+            .chain(removed.keys().map(|key| (key.clone(), PortState::Removed)))
+            .collect();
+        for port_info in ports {
+            let key = device_key(self.key_by, &port_info);
+            let state = if failed.contains_key(&port_info.port_name) {
+                PortState::Failed
+            } else {
+                PortState::Available
+            };
+            status.entry(key).or_insert(state);
+        }
+        status.into_iter().collect()
+    }
+
+    ///Keys of devices removed with `remove_device`, ignored by the polling thread.
+    pub fn removed_devices(&self) -> Vec<String> {
+        lock(&self.removed).keys().cloned().collect()
+    }
+}
+
+impl<T> Default for SerialDeviceManager<T>
+where
+    T: SerialDevice + 'static,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for SerialDeviceManager<T>
+where
+    T: SerialDevice + 'static,
+{
+    fn drop(&mut self) {
+        self.shutdown();
+        if let Some(poller) = self.poller.take() {
+            let _ = poller.join();
+        }
+    }
+}
+
+//Map key for a port under the given strategy
+fn device_key(key_by: KeyStrategy, port_info: &SerialPortInfo) -> String {
+    match (key_by, &port_info.port_type) {
+        (KeyStrategy::UsbSerial, SerialPortType::UsbPort(usb)) => usb
+            .serial_number
+            .clone()
+            .unwrap_or_else(|| port_info.port_name.clone()),
+        _ => port_info.port_name.clone(),
+    }
+}
+
+//Map changes needed to match the enumerated ports
+struct PortDiff<'a> {
+    removed: Vec<String>, //keys no longer enumerated
+    added: Vec<(String, &'a SerialPortInfo)>, //new keys, or known keys on a new port name
+}
+
+//Shared by every path that triggers an enumeration, so they agree on what changed.
+//Keys the user removed are left alone either way.
+fn diff_ports<'a, D>(
+    devices: &BTreeMap<String, DeviceEntry<D>>,
+    keyed_ports: &BTreeMap<String, &'a SerialPortInfo>,
+    removed: &BTreeMap<String, bool>,
+) -> PortDiff<'a> {
+    let gone = devices
+        .keys()
+        .filter(|key| !keyed_ports.contains_key(*key) && !removed.contains_key(*key))
+        .cloned()
+        .collect();
+    let added = keyed_ports
+        .iter()
+        .filter(|(key, port_info)| {
+            //same device showing up on a new port name needs a fresh handle
+            let known = devices
+                .get(*key)
+                .is_some_and(|entry| entry.port_name == port_info.port_name);
+            !known && !removed.contains_key(*key)
+        })
+        .map(|(key, port_info)| (key.clone(), *port_info))
+        .collect();
+    PortDiff { removed: gone, added }
+}
+
+//Runs the keep-alive check if it's due, dropping devices that reached the failure threshold.
+//The map is only locked to snapshot and evict, not while the checks talk to the devices.
+fn evict_unresponsive<D>(
+    state: &mut KeepAliveState<D>,
+    devices: &Mutex<BTreeMap<String, DeviceEntry<D>>>,
+    failed: &Mutex<HashMap<String, FailedOpen>>,
+) -> Vec<DeviceEvent> {
+    if state
+        .last_run
+        .is_some_and(|last_run| last_run.elapsed() < state.keep_alive.interval)
+    {
+        return Vec::new();
+    }
+    state.last_run = Some(Instant::now());
+
+    let snapshot: Vec<(String, Arc<Mutex<D>>)> = lock(devices)
+        .iter()
+        .map(|(key, entry)| (key.clone(), entry.device.clone()))
+        .collect();
+    state
+        .failures
+        .retain(|key, _| snapshot.iter().any(|(known, _)| known == key));
+
+    let mut unresponsive = Vec::new();
+    for (key, device) in snapshot {
+        //a device someone else has locked is in use, check it next time
+        let mut device = match device.try_lock() {
+            Ok(device) => device,
+            Err(TryLockError::Poisoned(poisoned)) => poisoned.into_inner(),
+            Err(TryLockError::WouldBlock) => continue,
+        };
+        if (state.keep_alive.check)(&mut device) {
+            state.failures.remove(&key);
+            continue;
+        }
+        let failures = state.failures.entry(key.clone()).or_insert(0);
+        *failures += 1;
+        if *failures >= state.keep_alive.max_failures {
+            state.failures.remove(&key);
+            unresponsive.push(key);
+        }
+    }
+
+    let mut devices = lock(devices);
+    let mut failed = lock(failed);
+    let mut evicted = Vec::new();
+    for key in unresponsive {
+        if let Some(entry) = devices.remove(&key) {
+            eprintln!("{} stopped responding, dropping it", key);
+            failed.insert(
+                entry.port_name,
+                FailedOpen {
+                    last_attempt: Instant::now(),
+                    attempts: 1,
+                },
+            );
+            evicted.push(DeviceEvent::Disconnected(key));
+        }
+    }
+    evicted
+}
+
+//Hand changes to both the event subscribers and the drain_changes queue
+fn publish(
+    subscribers: &Mutex<Vec<Sender<DeviceEvent>>>,
+    changes: &Mutex<Vec<DeviceChange>>,
+    events: &[DeviceEvent],
+) {
+    lock(changes)
+        .extend(events.iter().map(|event| match event {
+            DeviceEvent::Connected(key) => DeviceChange::Added(key.clone()),
+            DeviceEvent::Disconnected(key) => DeviceChange::Removed(key.clone()),
+        }));
+
+    //drop subscribers whose receiver has gone away
+    lock(subscribers)
+        .retain(|subscriber| events.iter().all(|event| subscriber.send(event.clone()).is_ok()));
+}
+
+//Locks `mutex` even if a panicking holder poisoned it. The maps stay consistent between
+//statements, so a panic in one poll or caller shouldn't take every later accessor with it.
+fn lock<M>(mutex: &Mutex<M>) -> MutexGuard<'_, M> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+//Mutable access to an unshared mutex, recovering poisoning the same way as `lock`
+fn lock_mut<M>(mutex: &mut Mutex<M>) -> &mut M {
+    mutex.get_mut().unwrap_or_else(PoisonError::into_inner)
+}
+
+//Best-effort text of a caught panic payload
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(message) = panic.downcast_ref::<&str>() {
+        message
+    } else if let Some(message) = panic.downcast_ref::<String>() {
+        message
+    } else {
+        "unknown panic"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use my_device::error::DeviceError;
+    use serialport::UsbPortInfo;
+    use std::collections::BTreeSet;
+    use std::time::Instant;
+
+    fn usb_port(port_name: &str, serial: Option<&str>) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: String::from(port_name),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid: 0x0483,
+                pid: 0x5740,
+                serial_number: serial.map(String::from),
+                manufacturer: None,
+                product: None,
+            }),
+        }
+    }
+
+    fn entry(port_name: &str) -> DeviceEntry<()> {
+        DeviceEntry {
+            port_name: String::from(port_name),
+            serial: None,
+            device: Arc::new(Mutex::new(())),
+        }
+    }
+
+    fn keyed(ports: &[SerialPortInfo]) -> BTreeMap<String, &SerialPortInfo> {
+        ports
+            .iter()
+            .map(|info| (device_key(KeyStrategy::UsbSerial, info), info))
+            .collect()
     }
 
-    ///Get a single device by the port/path identifier.
-    pub fn get_device(&self, port_name: &str) -> Option<Arc<Mutex<T::Device>>> {
-        self.devices.lock().unwrap().get(port_name).cloned()
+    fn added_keys(diff: &PortDiff) -> Vec<String> {
+        diff.added.iter().map(|(key, _)| key.clone()).collect()
     }
 
-    pub fn remove_device(&mut self, port_name: &str) -> bool {
-        self.devices.lock().unwrap().remove(port_name);
-        self.removed.insert(String::from(port_name), true);
+    //Ports currently held by a MockHandle, opens fail while a port is held like on the OS
+    static HELD: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+    static OPENS: Mutex<BTreeMap<String, usize>> = Mutex::new(BTreeMap::new());
+
+    struct MockDevice;
+
+    struct MockHandle(String);
+
+    impl Drop for MockHandle {
+        fn drop(&mut self) {
+            lock(&HELD).remove(&self.0);
+        }
+    }
+
+    //Ports named SLOW* take a while to open, like a driver loading firmware
+    impl SerialDevice for MockDevice {
+        type Device = MockHandle;
+
+        fn open(path: &str) -> Result<Mutex<MockHandle>, DeviceError> {
+            *lock(&OPENS).entry(String::from(path)).or_insert(0) += 1;
+            if path.starts_with("SLOW") {
+                thread::sleep(Duration::from_millis(200));
+            }
+            if !lock(&HELD).insert(String::from(path)) {
+                return Err(DeviceError::Io(serialport::Error::new(
+                    serialport::ErrorKind::NoDevice,
+                    "already open",
+                )));
+            }
+            Ok(Mutex::new(MockHandle(String::from(path))))
+        }
+
+        const VID: u16 = 0x0483;
+        const PID: u16 = 0x5740;
+        const BAUD_RATE: u32 = 115_200;
+    }
+
+    fn opens(path: &str) -> usize {
+        lock(&OPENS).get(path).copied().unwrap_or(0)
+    }
+
+    //Manager polling every few ms over a fixed set of ports
+    fn mock_manager(port_names: &[&str]) -> SerialDeviceManager<MockDevice> {
+        let config = ManagerConfig {
+            poll_interval: Duration::from_millis(5),
+            ..ManagerConfig::default()
+        };
+        mock_manager_with(port_names, config)
+    }
+
+    fn mock_manager_with(port_names: &[&str], config: ManagerConfig) -> SerialDeviceManager<MockDevice> {
+        let ports: Vec<SerialPortInfo> = port_names.iter().map(|name| usb_port(name, None)).collect();
+        SerialDeviceManager::with_port_source(config, Arc::new(move || Ok(ports.clone())))
+    }
+
+    //Polls until `done` holds, false if it still doesn't after two seconds
+    fn eventually(done: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while !done() {
+            if Instant::now() > deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
         true
     }
+
+    #[test]
+    fn drop_stops_polling_thread() {
+        let manager = mock_manager(&[]);
+        let start = Instant::now();
+        drop(manager);
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn shutdown_keeps_found_devices() {
+        let manager = mock_manager(&["COM_SHUTDOWN"]);
+        assert!(eventually(|| manager.get_device("COM_SHUTDOWN").is_some()));
+        manager.shutdown();
+        thread::sleep(Duration::from_millis(250));
+        assert_eq!(manager.get_devices(), vec!["COM_SHUTDOWN"]);
+    }
+
+    #[test]
+    fn poll_interval_can_be_changed() {
+        let manager: SerialDeviceManager<MockDevice> =
+            SerialDeviceManager::with_poll_interval(Duration::from_millis(250));
+        assert_eq!(manager.poll_interval(), Duration::from_millis(250));
+        manager.set_poll_interval(Duration::from_millis(20));
+        assert_eq!(manager.poll_interval(), Duration::from_millis(20));
+    }
+
+    #[test]
+    fn removed_device_is_not_reopened() {
+        let mut manager = mock_manager(&["COM_REMOVED"]);
+        assert!(eventually(|| manager.get_device("COM_REMOVED").is_some()));
+        assert!(manager.remove_device("COM_REMOVED"));
+        thread::sleep(Duration::from_millis(50));
+        assert!(manager.get_device("COM_REMOVED").is_none());
+    }
+
+    #[test]
+    fn subscribers_see_connect_and_disconnect() {
+        let ports = Arc::new(Mutex::new(Vec::new()));
+        let source = ports.clone();
+        let config = ManagerConfig {
+            poll_interval: Duration::from_millis(5),
+            ..ManagerConfig::default()
+        };
+        let manager: SerialDeviceManager<MockDevice> = SerialDeviceManager::with_port_source(
+            config,
+            Arc::new(move || Ok(lock(&source).clone())),
+        );
+        let events = manager.subscribe();
+        let timeout = Duration::from_secs(2);
+        lock(&ports).push(usb_port("COM_EVENTS", None));
+        assert_eq!(events.recv_timeout(timeout), Ok(DeviceEvent::Connected(String::from("COM_EVENTS"))));
+        lock(&ports).clear();
+        assert_eq!(events.recv_timeout(timeout), Ok(DeviceEvent::Disconnected(String::from("COM_EVENTS"))));
+    }
+
+    //MockDevice under a second product id, i.e. a board revision with a new descriptor
+    struct DualIdDevice;
+
+    impl SerialDevice for DualIdDevice {
+        type Device = MockHandle;
+
+        fn open(path: &str) -> Result<Mutex<MockHandle>, DeviceError> {
+            MockDevice::open(path)
+        }
+
+        const VID: u16 = 0x0483;
+        const PID: u16 = 0x5740;
+        const BAUD_RATE: u32 = 115_200;
+
+        fn usb_ids() -> &'static [(u16, u16)] {
+            &[(0x0483, 0x5740), (0x0483, 0xDF11)]
+        }
+    }
+
+    fn usb_port_with_ids(port_name: &str, vid: u16, pid: u16) -> SerialPortInfo {
+        let mut info = usb_port(port_name, None);
+        if let SerialPortType::UsbPort(usb) = &mut info.port_type {
+            usb.vid = vid;
+            usb.pid = pid;
+        }
+        info
+    }
+
+    #[test]
+    fn filter_ports_matches_any_usb_id() {
+        let ports = vec![
+            usb_port_with_ids("COM1", 0x0483, 0x5740),
+            usb_port_with_ids("COM2", 0x0483, 0xDF11),
+            usb_port_with_ids("COM3", 0x1234, 0x5740),
+            SerialPortInfo {
+                port_name: String::from("COM4"),
+                port_type: SerialPortType::PciPort,
+            },
+        ];
+        let names = |ports: Vec<SerialPortInfo>| -> Vec<String> {
+            ports.into_iter().map(|info| info.port_name).collect()
+        };
+        assert_eq!(names(SerialDeviceManager::<DualIdDevice>::filter_ports(ports.clone())), vec!["COM1", "COM2"]);
+        assert_eq!(names(SerialDeviceManager::<MockDevice>::filter_ports(ports)), vec!["COM1"]);
+    }
+
+    #[test]
+    fn scan_lists_only_matching_ports() {
+        //whatever the machine running the tests has plugged in
+        for info in SerialDeviceManager::<MockDevice>::scan() {
+            assert_eq!(SerialDeviceManager::<MockDevice>::filter_ports(vec![info]).len(), 1);
+        }
+    }
+
+    #[test]
+    fn devices_keyed_by_usb_serial() {
+        let ports = vec![usb_port("COM_KEYED", Some("SN_KEYED"))];
+        let config = ManagerConfig {
+            poll_interval: Duration::from_millis(5),
+            key_by: KeyStrategy::UsbSerial,
+            ..ManagerConfig::default()
+        };
+        let manager: SerialDeviceManager<MockDevice> =
+            SerialDeviceManager::with_port_source(config, Arc::new(move || Ok(ports.clone())));
+        assert!(eventually(|| manager.get_device("SN_KEYED").is_some()));
+        assert_eq!(manager.get_devices(), vec!["SN_KEYED"]);
+        assert!(manager.get_device("COM_KEYED").is_none());
+    }
+
+    #[test]
+    fn device_moving_to_a_new_port_keeps_one_entry() {
+        let ports = Arc::new(Mutex::new(vec![usb_port("COM_MOVE1", Some("SN_MOVE"))]));
+        let source = ports.clone();
+        let config = ManagerConfig {
+            poll_interval: Duration::from_millis(5),
+            key_by: KeyStrategy::UsbSerial,
+            ..ManagerConfig::default()
+        };
+        let manager: SerialDeviceManager<MockDevice> =
+            SerialDeviceManager::with_port_source(config, Arc::new(move || Ok(lock(&source).clone())));
+        let events = manager.subscribe();
+        let timeout = Duration::from_secs(2);
+        assert_eq!(events.recv_timeout(timeout), Ok(DeviceEvent::Connected(String::from("SN_MOVE"))));
+
+        *lock(&ports) = vec![usb_port("COM_MOVE2", Some("SN_MOVE"))];
+        assert_eq!(events.recv_timeout(timeout), Ok(DeviceEvent::Disconnected(String::from("SN_MOVE"))));
+        assert_eq!(events.recv_timeout(timeout), Ok(DeviceEvent::Connected(String::from("SN_MOVE"))));
+        assert_eq!(manager.get_devices(), vec!["SN_MOVE"]);
+        let port_name = lock(&manager.devices).get("SN_MOVE").map(|entry| entry.port_name.clone());
+        assert_eq!(port_name.as_deref(), Some("COM_MOVE2"));
+        assert_eq!(lock(&manager.get_device("SN_MOVE").unwrap()).0, "COM_MOVE2");
+        thread::sleep(Duration::from_millis(50));
+        assert!(events.try_recv().is_err());
+    }
+
+    #[test]
+    fn port_status_reports_open_and_removed_devices() {
+        let mut manager = mock_manager(&["COM_STATUS"]);
+        assert!(manager.wait_for(|key| key == "COM_STATUS", Duration::from_secs(2)).is_some());
+        assert_eq!(manager.port_status(), vec![(String::from("COM_STATUS"), PortState::Open)]);
+        manager.remove_device("COM_STATUS");
+        assert_eq!(manager.port_status(), vec![(String::from("COM_STATUS"), PortState::Removed)]);
+    }
+
+    #[test]
+    fn diff_ports_adds_new_and_removes_gone_devices() {
+        let ports = [usb_port("COM3", Some("A")), usb_port("COM4", Some("B"))];
+        let devices = BTreeMap::from([
+            (String::from("A"), entry("COM3")),
+            (String::from("C"), entry("COM5")),
+        ]);
+        let diff = diff_ports(&devices, &keyed(&ports), &BTreeMap::new());
+        assert_eq!(diff.removed, vec!["C"]);
+        assert_eq!(added_keys(&diff), vec!["B"]);
+    }
+
+    #[test]
+    fn diff_ports_reopens_known_device_on_new_port_name() {
+        let ports = [usb_port("COM7", Some("A"))];
+        let devices = BTreeMap::from([(String::from("A"), entry("COM3"))]);
+        let diff = diff_ports(&devices, &keyed(&ports), &BTreeMap::new());
+        assert!(diff.removed.is_empty());
+        assert_eq!(diff.added.len(), 1);
+        assert_eq!(diff.added[0].1.port_name, "COM7");
+    }
+
+    #[test]
+    fn diff_ports_leaves_removed_keys_alone() {
+        let ports = [usb_port("COM3", Some("A"))];
+        let devices = BTreeMap::from([(String::from("B"), entry("COM4"))]);
+        let removed = BTreeMap::from([(String::from("A"), true), (String::from("B"), true)]);
+        let diff = diff_ports(&devices, &keyed(&ports), &removed);
+        assert!(diff.removed.is_empty());
+        assert!(diff.added.is_empty());
+    }
+
+    #[test]
+    fn evict_unresponsive_drops_device_after_max_failures() {
+        let devices = Mutex::new(BTreeMap::from([
+            (String::from("A"), DeviceEntry {
+                port_name: String::from("COM3"),
+                serial: None,
+                device: Arc::new(Mutex::new(true)),
+            }),
+            (String::from("B"), DeviceEntry {
+                port_name: String::from("COM4"),
+                serial: None,
+                device: Arc::new(Mutex::new(false)),
+            }),
+        ]));
+        let failed = Mutex::new(HashMap::new());
+        let mut state = KeepAliveState {
+            keep_alive: KeepAlive {
+                interval: Duration::ZERO,
+                max_failures: 2,
+                check: Box::new(|responsive: &mut bool| *responsive),
+            },
+            last_run: None,
+            failures: HashMap::new(),
+        };
+        assert!(evict_unresponsive(&mut state, &devices, &failed).is_empty());
+        let evicted = evict_unresponsive(&mut state, &devices, &failed);
+        assert_eq!(evicted, vec![DeviceEvent::Disconnected(String::from("B"))]);
+        assert_eq!(lock(&devices).keys().collect::<Vec<_>>(), vec!["A"]);
+        assert!(lock(&failed).contains_key("COM4"));
+    }
+
+    #[test]
+    fn device_key_falls_back_to_port_name() {
+        let port = usb_port("COM3", Some("A"));
+        assert_eq!(device_key(KeyStrategy::UsbSerial, &port), "A");
+        assert_eq!(device_key(KeyStrategy::PortName, &port), "COM3");
+        assert_eq!(device_key(KeyStrategy::UsbSerial, &usb_port("COM4", None)), "COM4");
+    }
+
+    #[test]
+    fn wait_for_device_returns_first_device_or_times_out() {
+        let empty = mock_manager(&[]);
+        let start = Instant::now();
+        assert!(empty.wait_for_device(Duration::from_millis(50)).is_none());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        let manager = mock_manager(&["COM_WAIT"]);
+        let device = manager.wait_for_device(Duration::from_secs(2)).unwrap();
+        assert_eq!(lock(&device).0, "COM_WAIT");
+    }
+
+    #[test]
+    fn enumeration_errors_do_not_stop_polling() {
+        use std::sync::atomic::AtomicUsize;
+        let calls = Arc::new(AtomicUsize::new(0));
+        let source_calls = calls.clone();
+        let config = ManagerConfig {
+            poll_interval: Duration::from_millis(5),
+            ..ManagerConfig::default()
+        };
+        let manager: SerialDeviceManager<MockDevice> = SerialDeviceManager::with_port_source(
+            config,
+            Arc::new(move || {
+                //the first few enumerations fail, like while a driver is loading
+                if source_calls.fetch_add(1, Ordering::SeqCst) < 3 {
+                    return Err(serialport::Error::new(serialport::ErrorKind::Unknown, "driver loading"));
+                }
+                Ok(vec![usb_port("COM_ENUM_ERR", None)])
+            }),
+        );
+        assert!(eventually(|| manager.get_device("COM_ENUM_ERR").is_some()));
+        assert!(calls.load(Ordering::SeqCst) > 3);
+    }
+
+    #[test]
+    fn drain_changes_empties_the_queue() {
+        let manager = mock_manager(&["COM_DRAIN"]);
+        //changes are queued after the map is updated, so wait for the queue itself
+        assert!(eventually(|| !manager.changes.lock().unwrap().is_empty()));
+        assert_eq!(manager.drain_changes(), vec![DeviceChange::Added(String::from("COM_DRAIN"))]);
+        thread::sleep(Duration::from_millis(50));
+        assert!(manager.drain_changes().is_empty());
+    }
+
+    #[test]
+    fn drain_changes_returns_every_change_in_order() {
+        let ports = Arc::new(Mutex::new(vec![usb_port("COM_DRAIN1", None)]));
+        let source = ports.clone();
+        let config = ManagerConfig {
+            poll_interval: Duration::from_millis(5),
+            ..ManagerConfig::default()
+        };
+        let manager: SerialDeviceManager<MockDevice> =
+            SerialDeviceManager::with_port_source(config, Arc::new(move || Ok(lock(&source).clone())));
+        assert!(eventually(|| manager.is_connected("COM_DRAIN1")));
+        lock(&ports).push(usb_port("COM_DRAIN2", None));
+        assert!(eventually(|| manager.is_connected("COM_DRAIN2")));
+        lock(&ports).remove(0);
+        assert!(eventually(|| lock(&manager.changes).len() == 3));
+        let expected = vec![
+            DeviceChange::Added(String::from("COM_DRAIN1")),
+            DeviceChange::Added(String::from("COM_DRAIN2")),
+            DeviceChange::Removed(String::from("COM_DRAIN1")),
+        ];
+        assert_eq!(manager.drain_changes(), expected);
+        assert!(lock(&manager.changes).is_empty());
+        assert!(manager.drain_changes().is_empty());
+    }
+
+    #[test]
+    fn port_held_elsewhere_is_backed_off() {
+        lock(&HELD).insert(String::from("COM_HELD"));
+        let config = ManagerConfig {
+            poll_interval: Duration::from_millis(5),
+            open_backoff: Duration::from_secs(30),
+            ..ManagerConfig::default()
+        };
+        let manager = mock_manager_with(&["COM_HELD"], config);
+        thread::sleep(Duration::from_millis(100));
+        assert!(manager.get_devices().is_empty());
+        assert_eq!(opens("COM_HELD"), 1);
+        lock(&HELD).remove("COM_HELD");
+    }
+
+    #[test]
+    fn device_count_and_is_connected_track_the_map() {
+        let empty = mock_manager(&[]);
+        thread::sleep(Duration::from_millis(20));
+        assert_eq!(empty.device_count(), 0);
+        assert!(!empty.is_connected("COM_COUNT"));
+
+        let mut manager = mock_manager(&["COM_COUNT"]);
+        assert!(eventually(|| manager.is_connected("COM_COUNT")));
+        assert_eq!(manager.device_count(), 1);
+        assert!(manager.is_connected("COM_COUNT"));
+        manager.remove_device("COM_COUNT");
+        assert_eq!(manager.device_count(), 0);
+        assert!(!manager.is_connected("COM_COUNT"));
+    }
+
+    #[test]
+    fn with_device_gives_up_while_another_holder_has_the_lock() {
+        let manager = mock_manager(&["COM_BUSY"]);
+        assert!(eventually(|| manager.is_connected("COM_BUSY")));
+        let device = manager.get_device("COM_BUSY").unwrap();
+        let timeout = Duration::from_millis(30);
+        assert_eq!(manager.with_device("COM_BUSY", timeout, |dev| dev.0.clone()).unwrap(), "COM_BUSY");
+
+        let held = lock(&device);
+        assert!(matches!(manager.with_device("COM_BUSY", timeout, |_| ()), Err(ManagerError::Busy(_))));
+        drop(held);
+        assert!(matches!(manager.with_device("COM_NONE", timeout, |_| ()), Err(ManagerError::NotFound(_))));
+    }
+
+    #[test]
+    fn failed_port_is_retried_after_its_backoff() {
+        lock(&HELD).insert(String::from("COM_RETRY"));
+        let config = ManagerConfig {
+            poll_interval: Duration::from_millis(5),
+            open_backoff: Duration::from_millis(100),
+            ..ManagerConfig::default()
+        };
+        let manager = mock_manager_with(&["COM_RETRY"], config);
+        thread::sleep(Duration::from_millis(50));
+        //still backing off, so the port hasn't been hammered
+        assert_eq!(opens("COM_RETRY"), 1);
+        lock(&HELD).remove("COM_RETRY");
+        assert!(eventually(|| manager.is_connected("COM_RETRY")));
+        assert_eq!(opens("COM_RETRY"), 2);
+    }
+
+    #[test]
+    fn removed_devices_lists_the_blacklist_until_unremoved() {
+        let mut manager = mock_manager(&["COM_BL1", "COM_BL2"]);
+        assert!(eventually(|| manager.is_connected("COM_BL1")));
+        assert!(eventually(|| manager.is_connected("COM_BL2")));
+        assert!(manager.removed_devices().is_empty());
+        manager.remove_device("COM_BL2");
+        manager.remove_device("COM_BL1");
+        assert_eq!(manager.removed_devices(), vec!["COM_BL1", "COM_BL2"]);
+        manager.unremove_device("COM_BL1");
+        assert_eq!(manager.removed_devices(), vec!["COM_BL2"]);
+    }
+
+    #[test]
+    fn with_first_device_runs_on_the_locked_device() {
+        let empty = mock_manager(&[]);
+        assert_eq!(empty.with_first_device(|_| ()), None);
+
+        let manager = mock_manager(&["COM_FIRST"]);
+        assert!(eventually(|| manager.is_connected("COM_FIRST")));
+        let device = manager.get_device("COM_FIRST").unwrap();
+        manager.with_first_device(|dev| dev.0.push_str("_TAGGED")).unwrap();
+        assert_eq!(lock(&device).0, "COM_FIRST_TAGGED");
+        //put the name back so dropping the handle releases the right port
+        manager.with_first_device(|dev| dev.0.truncate("COM_FIRST".len())).unwrap();
+    }
+
+    #[test]
+    fn poller_survives_a_panicking_poll() {
+        use std::sync::atomic::AtomicUsize;
+        let calls = Arc::new(AtomicUsize::new(0));
+        let source_calls = calls.clone();
+        let config = ManagerConfig {
+            poll_interval: Duration::from_millis(5),
+            ..ManagerConfig::default()
+        };
+        let manager: SerialDeviceManager<MockDevice> = SerialDeviceManager::with_port_source(
+            config,
+            Arc::new(move || {
+                if source_calls.fetch_add(1, Ordering::SeqCst) < 2 {
+                    panic!("enumeration bug");
+                }
+                Ok(vec![usb_port("COM_PANIC", None)])
+            }),
+        );
+        assert!(eventually(|| manager.is_connected("COM_PANIC")));
+        assert_eq!(manager.get_devices(), vec!["COM_PANIC"]);
+    }
+
+    #[test]
+    fn lock_recovers_a_poisoned_mutex() {
+        let mutex = Arc::new(Mutex::new(1));
+        let poisoner = mutex.clone();
+        let _ = thread::spawn(move || {
+            let _guard = poisoner.lock().unwrap();
+            panic!("poison");
+        })
+        .join();
+        assert!(mutex.is_poisoned());
+        assert_eq!(*lock(&mutex), 1);
+    }
+
+    #[test]
+    fn for_each_visits_every_device_and_collects_errors() {
+        let manager = mock_manager(&["COM_EACH1", "COM_EACH2"]);
+        assert!(eventually(|| manager.is_connected("COM_EACH1")));
+        assert!(eventually(|| manager.is_connected("COM_EACH2")));
+        let mut visited = Vec::new();
+        let errors = manager.for_each(|key, dev| {
+            visited.push(dev.0.clone());
+            if key == "COM_EACH2" {
+                return Err("no reply");
+            }
+            Ok(())
+        });
+        assert_eq!(visited, vec!["COM_EACH1", "COM_EACH2"]);
+        assert_eq!(errors, vec![(String::from("COM_EACH2"), "no reply")]);
+    }
+
+    #[test]
+    fn on_reconnect_runs_only_for_devices_seen_before() {
+        let ports = Arc::new(Mutex::new(vec![usb_port("COM_RECONNECT", None)]));
+        let source = ports.clone();
+        let config = ManagerConfig {
+            poll_interval: Duration::from_millis(5),
+            ..ManagerConfig::default()
+        };
+        let manager: SerialDeviceManager<MockDevice> =
+            SerialDeviceManager::with_port_source(config, Arc::new(move || Ok(lock(&source).clone())));
+        let (sender, reconnects) = mpsc::channel();
+        manager.on_reconnect(Box::new(move |key, dev| {
+            let _ = sender.send((String::from(key), dev.0.clone()));
+        }));
+        let events = manager.subscribe();
+        let timeout = Duration::from_secs(2);
+        assert_eq!(events.recv_timeout(timeout), Ok(DeviceEvent::Connected(String::from("COM_RECONNECT"))));
+        assert!(reconnects.try_recv().is_err());
+
+        let port = lock(&ports).remove(0);
+        assert_eq!(events.recv_timeout(timeout), Ok(DeviceEvent::Disconnected(String::from("COM_RECONNECT"))));
+        lock(&ports).push(port);
+        assert_eq!(
+            reconnects.recv_timeout(timeout),
+            Ok((String::from("COM_RECONNECT"), String::from("COM_RECONNECT")))
+        );
+    }
+
+    #[test]
+    fn slow_reconnect_hook_does_not_block_accessors() {
+        let ports = Arc::new(Mutex::new(vec![usb_port("COM_SLOW_HOOK", None)]));
+        let source = ports.clone();
+        let config = ManagerConfig {
+            poll_interval: Duration::from_millis(5),
+            ..ManagerConfig::default()
+        };
+        let manager: SerialDeviceManager<MockDevice> =
+            SerialDeviceManager::with_port_source(config, Arc::new(move || Ok(lock(&source).clone())));
+        let (started, hook_started) = mpsc::channel();
+        let (release, released) = mpsc::channel::<()>();
+        manager.on_reconnect(Box::new(move |_, _| {
+            let _ = started.send(());
+            let _ = released.recv_timeout(Duration::from_secs(5));
+        }));
+        let events = manager.subscribe();
+        let timeout = Duration::from_secs(2);
+        assert_eq!(events.recv_timeout(timeout), Ok(DeviceEvent::Connected(String::from("COM_SLOW_HOOK"))));
+        let port = lock(&ports).remove(0);
+        assert_eq!(events.recv_timeout(timeout), Ok(DeviceEvent::Disconnected(String::from("COM_SLOW_HOOK"))));
+        lock(&ports).push(port);
+
+        assert_eq!(hook_started.recv_timeout(timeout), Ok(()));
+        let start = Instant::now();
+        assert_eq!(manager.device_count(), 0);
+        assert!(manager.get_devices().is_empty());
+        assert!(start.elapsed() < Duration::from_secs(1));
+        release.send(()).unwrap();
+        assert_eq!(events.recv_timeout(timeout), Ok(DeviceEvent::Connected(String::from("COM_SLOW_HOOK"))));
+    }
+
+    #[test]
+    fn debug_log_shows_ids_and_whether_they_matched() {
+        type Manager = SerialDeviceManager<MockDevice>;
+        assert_eq!(Manager::describe_port(&usb_port("COM3", None)), "COM3: USB 0483:5740 matched");
+        assert_eq!(
+            Manager::describe_port(&usb_port_with_ids("COM4", 0x0483, 0xDF11)),
+            "COM4: USB 0483:DF11 skipped"
+        );
+        let pci = SerialPortInfo {
+            port_name: String::from("COM1"),
+            port_type: SerialPortType::PciPort,
+        };
+        assert_eq!(Manager::describe_port(&pci), "COM1: PciPort skipped");
+    }
+
+    #[test]
+    fn opens_each_port_once() {
+        let manager = mock_manager(&["COM_ONCE"]);
+        assert!(eventually(|| manager.is_connected("COM_ONCE")));
+        thread::sleep(Duration::from_millis(100));
+        assert!(manager.is_connected("COM_ONCE"));
+        assert_eq!(opens("COM_ONCE"), 1);
+    }
+
+    #[test]
+    fn slow_open_is_not_repeated_or_dropped() {
+        let manager = mock_manager(&["SLOW_OPEN"]);
+        let events = manager.subscribe();
+        assert!(eventually(|| manager.is_connected("SLOW_OPEN")));
+        thread::sleep(Duration::from_millis(300));
+        assert!(manager.is_connected("SLOW_OPEN"));
+        assert_eq!(opens("SLOW_OPEN"), 1);
+        let events: Vec<DeviceEvent> = events.try_iter().collect();
+        assert_eq!(events, vec![DeviceEvent::Connected(String::from("SLOW_OPEN"))]);
+    }
+
+    #[test]
+    fn fast_port_is_not_held_up_by_a_slow_open() {
+        let manager = mock_manager(&["SLOW_BESIDE_FAST", "COM_BESIDE_SLOW"]);
+        assert!(eventually(|| manager.is_connected("COM_BESIDE_SLOW")));
+        //the slow open takes 200ms, well after the fast port is in the map
+        assert_eq!(manager.get_devices(), vec!["COM_BESIDE_SLOW"]);
+        assert!(eventually(|| manager.is_connected("SLOW_BESIDE_FAST")));
+        assert_eq!(opens("SLOW_BESIDE_FAST"), 1);
+    }
+
+    #[test]
+    fn port_being_opened_is_not_pending() {
+        let config = ManagerConfig {
+            poll_interval: Duration::from_millis(5),
+            max_devices: Some(1),
+            ..ManagerConfig::default()
+        };
+        //keys are opened in order, so the slow port takes the only slot
+        let manager = mock_manager_with(&["SLOW_MAX1", "WAIT_MAX2"], config);
+        //several polls run during the 200ms open
+        thread::sleep(Duration::from_millis(50));
+        assert!(!manager.is_connected("SLOW_MAX1"));
+        assert_eq!(manager.pending_ports(), vec!["WAIT_MAX2"]);
+        assert!(eventually(|| manager.is_connected("SLOW_MAX1")));
+        assert_eq!(manager.pending_ports(), vec!["WAIT_MAX2"]);
+        assert_eq!(opens("SLOW_MAX1"), 1);
+        assert_eq!(opens("WAIT_MAX2"), 0);
+    }
+
+    #[test]
+    fn max_devices_holds_extra_ports_until_a_slot_frees() {
+        let config = ManagerConfig {
+            poll_interval: Duration::from_millis(5),
+            max_devices: Some(1),
+            ..ManagerConfig::default()
+        };
+        let mut manager = mock_manager_with(&["COM_MAX1", "COM_MAX2"], config);
+        assert!(eventually(|| manager.is_connected("COM_MAX1")));
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(manager.get_devices(), vec!["COM_MAX1"]);
+        assert_eq!(manager.pending_ports(), vec!["COM_MAX2"]);
+        assert_eq!(opens("COM_MAX2"), 0);
+
+        manager.remove_device("COM_MAX1");
+        assert!(eventually(|| manager.is_connected("COM_MAX2")));
+        assert_eq!(manager.device_count(), 1);
+    }
+
+    #[test]
+    fn wait_for_matches_a_particular_unit() {
+        let ports = vec![usb_port("COM_UNIT_A", Some("SN_A")), usb_port("COM_UNIT_B", Some("SN_B"))];
+        let config = ManagerConfig {
+            poll_interval: Duration::from_millis(5),
+            key_by: KeyStrategy::UsbSerial,
+            ..ManagerConfig::default()
+        };
+        let manager: SerialDeviceManager<MockDevice> =
+            SerialDeviceManager::with_port_source(config, Arc::new(move || Ok(ports.clone())));
+        let device = manager.wait_for(|key| key == "SN_B", Duration::from_secs(2)).unwrap();
+        assert_eq!(lock(&device).0, "COM_UNIT_B");
+        assert!(manager.wait_for(|key| key == "SN_C", Duration::from_millis(50)).is_none());
+    }
+
+    #[test]
+    fn polling_thread_is_named() {
+        let manager = mock_manager(&[]);
+        let poller = manager.poller.as_ref().unwrap();
+        assert_eq!(poller.thread().name(), Some(POLLER_THREAD_NAME));
+    }
+
+    //MockDevice whose units report a serial derived from their port name
+    struct SerialReportingDevice;
+
+    impl SerialDevice for SerialReportingDevice {
+        type Device = MockHandle;
+
+        fn open(path: &str) -> Result<Mutex<MockHandle>, DeviceError> {
+            MockDevice::open(path)
+        }
+
+        const VID: u16 = 0x0483;
+        const PID: u16 = 0x5740;
+        const BAUD_RATE: u32 = 115_200;
+
+        fn read_serial(device: &mut MockHandle) -> Option<String> {
+            Some(format!("SN_{}", device.0))
+        }
+    }
+
+    #[test]
+    fn identical_units_keep_their_own_serials() {
+        let ports = vec![usb_port("COM_TWIN1", None), usb_port("COM_TWIN2", None)];
+        let config = ManagerConfig {
+            poll_interval: Duration::from_millis(5),
+            ..ManagerConfig::default()
+        };
+        let manager: SerialDeviceManager<SerialReportingDevice> =
+            SerialDeviceManager::with_port_source(config, Arc::new(move || Ok(ports.clone())));
+        assert!(manager.wait_for(|key| key == "COM_TWIN1", Duration::from_secs(2)).is_some());
+        assert!(manager.wait_for(|key| key == "COM_TWIN2", Duration::from_secs(2)).is_some());
+        assert_eq!(manager.get_device_serial("COM_TWIN1").as_deref(), Some("SN_COM_TWIN1"));
+        assert_eq!(manager.get_device_serial("COM_TWIN2").as_deref(), Some("SN_COM_TWIN2"));
+        assert_eq!(manager.get_device_serial("COM_NONE"), None);
+
+        let plain = mock_manager(&["COM_NO_SERIAL"]);
+        assert!(plain.wait_for(|key| key == "COM_NO_SERIAL", Duration::from_secs(2)).is_some());
+        assert_eq!(plain.get_device_serial("COM_NO_SERIAL"), None);
+    }
 }
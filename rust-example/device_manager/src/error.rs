@@ -0,0 +1,21 @@
+use std::fmt;
+
+///Errors returned by the SerialDeviceManager.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ManagerError {
+    ///No device is attached under the given key.
+    NotFound(String),
+    ///The device stayed locked by another holder for the whole timeout.
+    Busy(String),
+}
+
+impl fmt::Display for ManagerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ManagerError::NotFound(key) => write!(f, "No device attached as {}", key),
+            ManagerError::Busy(key) => write!(f, "Device {} is busy", key),
+        }
+    }
+}
+
+impl std::error::Error for ManagerError {}
@@ -0,0 +1,30 @@
+//Wakes the polling thread when the OS reports device nodes being created or removed.
+//Enumeration still happens on the polling thread, this only decides when it runs.
+
+use std::path::Path;
+use std::sync::mpsc::Sender;
+
+use notify::{recommended_watcher, Event, EventKind, RecursiveMode, Watcher as _};
+
+pub(crate) type Watcher = notify::RecommendedWatcher;
+
+//Where serial device nodes show up, None where there's nothing to watch
+#[cfg(target_os = "linux")]
+const DEVICE_DIR: Option<&str> = Some("/dev");
+#[cfg(not(target_os = "linux"))]
+const DEVICE_DIR: Option<&str> = None;
+
+//None if the platform or the watcher isn't supported, leaving plain polling
+pub(crate) fn watch(wake: Sender<()>) -> Option<Watcher> {
+    let dir = DEVICE_DIR?;
+    let mut watcher = recommended_watcher(move |event: notify::Result<Event>| {
+        if let Ok(event) = event {
+            if matches!(event.kind, EventKind::Create(_) | EventKind::Remove(_)) {
+                let _ = wake.send(());
+            }
+        }
+    })
+    .ok()?;
+    watcher.watch(Path::new(dir), RecursiveMode::NonRecursive).ok()?;
+    Some(watcher)
+}
@@ -1 +1,4 @@
 pub mod device_manager;
+pub mod error;
+#[cfg(feature = "hotplug")]
+mod hotplug;
@@ -0,0 +1,166 @@
+use std::time::{Duration, Instant};
+
+use serialport::FlowControl;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::time::timeout;
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+
+use crate::command::Command;
+use crate::error::DeviceError;
+use crate::my_device::{
+    parse_ack, parse_data, unterminated, Frame, LineEnding, LineSplitter, MyDevice, Protocol,
+    DEFAULT_READ_BUFFER_SIZE, DEFAULT_TIMEOUT,
+};
+use crate::parameters::Parameter;
+use crate::serial_device::SerialDevice;
+
+///Async counterpart of MyDevice for use on a tokio runtime.
+///
+///Reads are bounded with `tokio::time::timeout` instead of the blocking port timeout,
+///so a slow device doesn't stall the executor.
+pub struct AsyncMyDevice<S = SerialStream> {
+    path: String, //OS Path i.e. COM15(windows)
+    port: S,
+    timeout: Duration,
+    read_buffer_size: usize,
+    protocol: Protocol,
+    lines: LineSplitter,
+}
+
+impl MyDevice {
+    ///Open the device for async use. Must be called from within a tokio runtime.
+    pub fn open_async(path: &str) -> Result<AsyncMyDevice, DeviceError> {
+        let port = tokio_serial::new(path, MyDevice::BAUD_RATE)
+            .flow_control(FlowControl::None)
+            .open_native_async()?;
+        Ok(AsyncMyDevice::from_stream(path, port))
+    }
+}
+
+impl<S> AsyncMyDevice<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    ///Wrap any async byte stream, i.e. a mock in place of a real port.
+    pub fn from_stream(path: &str, port: S) -> Self {
+        AsyncMyDevice {
+            path: String::from(path),
+            port,
+            timeout: DEFAULT_TIMEOUT,
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            protocol: Protocol::default(),
+            lines: LineSplitter::default(),
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    ///Sets how long each read waits for the device.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn set_line_ending(&mut self, line_ending: LineEnding) {
+        self.lines.set_line_ending(line_ending);
+    }
+
+    async fn write_command(&mut self, cmd: &[u8]) -> Result<(), DeviceError> {
+        self.port.write_all(cmd).await?;
+        self.port.flush().await?;
+        Ok(())
+    }
+
+    //Same framing as the blocking read: one line within the timeout, anything after the
+    //line ending is dropped
+    async fn read_response(&mut self) -> Result<Frame, DeviceError> {
+        let deadline = Instant::now() + self.timeout;
+        let mut pending = Vec::new();
+        let mut buff: Vec<u8> = vec![0; self.read_buffer_size];
+        loop {
+            if let Some(frame) = self.lines.take_frame(&mut pending)? {
+                return Ok(frame);
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match timeout(remaining, self.port.read(buff.as_mut_slice())).await {
+                Ok(Ok(0)) | Err(_) => break,
+                Ok(Ok(n)) => pending.extend_from_slice(&buff[..n]),
+                Ok(Err(e)) => return Err(e.into()),
+            }
+        }
+        Err(unterminated(&pending))
+    }
+
+    async fn ack_call(&mut self, cmd: &[u8]) -> Result<String, DeviceError> {
+        self.write_command(cmd).await?;
+        let frame = self.read_response().await?;
+        parse_ack(frame.text.as_bytes()).map_err(|e| e.with_raw(&frame.raw))
+    }
+
+    async fn data_call(&mut self, cmd: &[u8]) -> Result<String, DeviceError> {
+        self.write_command(cmd).await?;
+        let frame = self.read_response().await?;
+        parse_data(frame.text.as_bytes(), &self.protocol).map_err(|e| e.with_raw(&frame.raw))
+    }
+
+    pub async fn led_on(&mut self) -> Result<String, DeviceError> {
+        self.ack_call(&Command::LedOn.to_bytes(&self.protocol)).await
+    }
+
+    pub async fn led_off(&mut self) -> Result<String, DeviceError> {
+        self.ack_call(&Command::LedOff.to_bytes(&self.protocol)).await
+    }
+
+    pub async fn get_param(&mut self, param: Parameter) -> Result<String, DeviceError> {
+        self.data_call(&Command::GetParam(param).to_bytes(&self.protocol)).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{duplex, DuplexStream};
+
+    //Device on one end of an in-memory pipe, the test plays the firmware on the other
+    fn mock_device() -> (AsyncMyDevice<DuplexStream>, DuplexStream) {
+        let (port, firmware) = duplex(256);
+        let mut device = AsyncMyDevice::from_stream("MOCK", port);
+        device.set_timeout(Duration::from_millis(50));
+        (device, firmware)
+    }
+
+    #[tokio::test]
+    async fn silence_is_a_timeout() {
+        let (mut device, _firmware) = mock_device();
+        assert!(matches!(device.led_on().await, Err(DeviceError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn partial_line_is_possibly_truncated() {
+        let (mut device, mut firmware) = mock_device();
+        firmware.write_all(b"O").await.unwrap();
+        match device.led_on().await {
+            Err(DeviceError::PossiblyTruncated(partial)) => assert_eq!(partial, "O"),
+            other => panic!("expected PossiblyTruncated, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn bytes_after_line_ending_are_dropped() {
+        let (mut device, mut firmware) = mock_device();
+        firmware.write_all(b"OK\nstale").await.unwrap();
+        assert_eq!(device.led_on().await.unwrap(), "OK");
+        let mut written = vec![0; 5];
+        firmware.read_exact(&mut written).await.unwrap();
+        assert_eq!(written, b"LED,1");
+    }
+
+    #[tokio::test]
+    async fn respects_line_ending() {
+        let (mut device, mut firmware) = mock_device();
+        device.set_line_ending(LineEnding::Any);
+        firmware.write_all(b" OK\r").await.unwrap();
+        assert_eq!(device.led_on().await.unwrap(), " OK");
+    }
+}
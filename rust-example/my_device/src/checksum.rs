@@ -0,0 +1,115 @@
+//CRC-16 (polynomial 0x8005, MSB first) used to validate device responses
+
+const POLYNOMIAL: u16 = 0x8005;
+const TERMINATOR: char = ';';
+
+//Bitwise reference implementation, only used to build the lookup table
+const fn crc_16_msb(b: u8, crc: u16) -> u16 {
+    let mut data = (b as u16) << 8;
+    let mut crc = crc;
+    let mut i = 0;
+    while i < 8 {
+        if ((data ^ crc) & 0x8000) != 0 {
+            crc = (crc << 1) ^ POLYNOMIAL;
+        } else {
+            crc <<= 1;
+        }
+        data <<= 1;
+        i += 1;
+    }
+    crc
+}
+
+const fn build_table() -> [u16; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        table[i] = crc_16_msb(i as u8, 0);
+        i += 1;
+    }
+    table
+}
+
+//CRC of each possible top byte, lets crc16 process a byte per lookup
+static CRC_TABLE: [u16; 256] = build_table();
+
+///Computes the CRC-16 of raw bytes.
+pub fn crc16(data: &[u8]) -> u16 {
+    crc16_update(0, data)
+}
+
+//Continues a CRC over more bytes, so a terminator can be added without copying the message
+fn crc16_update(crc: u16, data: &[u8]) -> u16 {
+    data.iter().fold(crc, |crc, &b| {
+        (crc << 8) ^ CRC_TABLE[usize::from((crc >> 8) as u8 ^ b)]
+    })
+}
+
+///Computes the CRC-16 of raw bytes with `terminator` appended, for firmware that frames
+///data lines with something other than `;`. See `Protocol::terminator`.
+pub fn checksum_with_terminator(data: &[u8], terminator: char) -> u16 {
+    crc16_update(crc16(data), terminator.encode_utf8(&mut [0; 4]).as_bytes())
+}
+
+///Computes the CRC-16 of raw bytes with the `;` terminator appended, as the device does.
+///Works for binary payloads that aren't valid UTF-8.
+pub fn compute_checksum_bytes(data: &[u8]) -> u16 {
+    checksum_with_terminator(data, TERMINATOR)
+}
+
+///Computes the CRC-16 of a message with the `;` terminator appended, as the device does.
+pub fn crc16_with_terminator(s: &str) -> u16 {
+    compute_checksum_bytes(s.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn terminator_is_checksummed_after_the_data() {
+        assert_eq!(compute_checksum_bytes(b"GET,LED_DRIVE,0,40"), crc16(b"GET,LED_DRIVE,0,40;"));
+        assert_eq!(checksum_with_terminator(b"GET|LED_DRIVE", '|'), crc16(b"GET|LED_DRIVE|"));
+        assert_eq!(checksum_with_terminator(b"A", '\u{00A7}'), crc16("A\u{00A7}".as_bytes()));
+    }
+
+    #[test]
+    fn known_check_value() {
+        //CRC-16/BUYPASS check value
+        assert_eq!(crc16(b"123456789"), 0xFEE8);
+    }
+
+    #[test]
+    fn str_and_bytes_helpers_agree() {
+        assert_eq!(crc16(b""), 0);
+        assert_eq!(crc16_with_terminator("SER_NUMBER"), crc16(b"SER_NUMBER;"));
+        assert_eq!(crc16_with_terminator("SER_NUMBER"), compute_checksum_bytes(b"SER_NUMBER"));
+    }
+
+    #[test]
+    fn table_matches_bitwise_reference() {
+        //xorshift keeps the inputs the same from run to run
+        let mut seed: u32 = 0x2545_F491;
+        let mut next = move || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed
+        };
+        for _ in 0..10_000 {
+            let len = (next() % 64) as usize;
+            let data: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+            let bitwise = data.iter().fold(0, |crc, &b| crc_16_msb(b, crc));
+            assert_eq!(crc16(&data), bitwise, "{:02X?}", data);
+        }
+    }
+
+    #[test]
+    fn bytes_checksum_handles_non_utf8_data() {
+        let binary = [0xFF, 0x00, 0xFE, b',']; //not valid UTF-8
+        assert_eq!(compute_checksum_bytes(&binary), crc16(&[0xFF, 0x00, 0xFE, b',', b';']));
+        for s in ["", "LED,1", "GET,CAL_SLOPE,0,1.05", "\u{00E9}"] {
+            assert_eq!(crc16_with_terminator(s), compute_checksum_bytes(s.as_bytes()), "{:?}", s);
+        }
+    }
+}
@@ -0,0 +1,73 @@
+use crate::my_device::Protocol;
+use crate::parameters::Parameter;
+
+//expected acks per command
+const LED_ACK: &str = "OK";
+const SAVE_ACK: &str = "OK";
+const DFU_ACK: &str = "OK";
+
+///Older firmware's save command, newer builds use "SAVE". See `Protocol::save_command`.
+pub const DEFAULT_SAVE_COMMAND: &str = "CAL,1,1";
+
+///Every command the device understands, serialized in one place.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    LedOn,
+    LedOff,
+    GetParam(Parameter),
+    SetParam(Parameter, String),
+    SaveParams,
+    EnterDfu,
+}
+
+impl Command {
+    ///wire format sent to a device speaking `protocol`
+    pub fn to_bytes(&self, protocol: &Protocol) -> Vec<u8> {
+        let cmd = match &self {
+            Command::LedOn => String::from("LED,1"),
+            Command::LedOff => String::from("LED,0"),
+            Command::GetParam(param) => String::from("GET,") + param.as_str(),
+            Command::SetParam(param, value) => String::from("SET,") + param.as_str() + "," + value,
+            Command::SaveParams => protocol.save_command.clone(),
+            Command::EnterDfu => String::from("DFU,0"),
+        };
+        cmd.into_bytes()
+    }
+
+    ///ack the device replies with, None for commands that reply with a data line
+    pub fn expected_ack(&self) -> Option<&'static str> {
+        match &self {
+            Command::LedOn | Command::LedOff => Some(LED_ACK),
+            Command::SaveParams => Some(SAVE_ACK),
+            Command::EnterDfu => Some(DFU_ACK),
+            Command::GetParam(_) | Command::SetParam(_, _) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn save_params_uses_protocol_save_command() {
+        let protocol = Protocol::default();
+        assert_eq!(Command::SaveParams.to_bytes(&protocol), DEFAULT_SAVE_COMMAND.as_bytes());
+        let protocol = Protocol {
+            save_command: String::from("SAVE"),
+            ..Protocol::default()
+        };
+        assert_eq!(Command::SaveParams.to_bytes(&protocol), b"SAVE");
+    }
+
+    #[test]
+    fn wire_format() {
+        let protocol = Protocol::default();
+        assert_eq!(Command::LedOn.to_bytes(&protocol), b"LED,1");
+        assert_eq!(Command::LedOff.to_bytes(&protocol), b"LED,0");
+        assert_eq!(Command::GetParam(Parameter::LedDrive).to_bytes(&protocol), b"GET,LED_DRIVE");
+        let set = Command::SetParam(Parameter::LedDrive, String::from("40"));
+        assert_eq!(set.to_bytes(&protocol), b"SET,LED_DRIVE,40");
+        assert_eq!(Command::EnterDfu.to_bytes(&protocol), b"DFU,0");
+    }
+}
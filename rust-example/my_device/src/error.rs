@@ -0,0 +1,150 @@
+use std::fmt;
+use std::io;
+use std::str;
+
+///Errors returned when talking to a device over its serial port.
+#[derive(Debug)]
+pub enum DeviceError {
+    ChecksumMismatch { expected: u16, received: String, raw: Vec<u8> },
+    InvalidParameter,
+    ReadOnlyParameter(String),
+    WriteOnlyParameter(String),
+    Rejected(String),
+    MalformedResponse { expected_fields: usize, got: usize, raw: Vec<u8> },
+    UnexpectedAck { expected: String, got: String },
+    Incomplete { expected: usize, lines: Vec<String> },
+    NotFound(String),
+    AmbiguousSerial { serial: String, ports: Vec<String> },
+    SettingsMismatch(Vec<String>),
+    BreakUnsupported(serialport::Error),
+    PossiblyTruncated(String),
+    Timeout,
+    Io(serialport::Error),
+    Utf8(str::Utf8Error),
+    Json(serde_json::Error),
+    Parse(String),
+}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceError::ChecksumMismatch { expected, received, .. } => {
+                write!(f, "Invalid Checksum: expected {:04X} but received {:?}", expected, received)
+            }
+            DeviceError::InvalidParameter => write!(f, "Invalid Parameter"),
+            DeviceError::ReadOnlyParameter(name) => write!(f, "Parameter {} is read-only", name),
+            DeviceError::WriteOnlyParameter(name) => write!(f, "Parameter {} is write-only", name),
+            DeviceError::MalformedResponse { expected_fields, got, .. } => {
+                write!(f, "Malformed response: expected {} fields but got {}", expected_fields, got)
+            }
+            DeviceError::Rejected(reply) => write!(f, "Device rejected command: {:?}", reply),
+            DeviceError::UnexpectedAck { expected, got } => {
+                write!(f, "Expected ack {:?} but got {:?}", expected, got)
+            }
+            DeviceError::Incomplete { expected, lines } => {
+                write!(f, "Expected {} lines but only read {}", expected, lines.len())
+            }
+            DeviceError::NotFound(serial) => write!(f, "No device with serial {}", serial),
+            DeviceError::AmbiguousSerial { serial, ports } => {
+                write!(f, "Serial {} matches several ports: {}", serial, ports.join(", "))
+            }
+            DeviceError::SettingsMismatch(mismatches) => {
+                write!(f, "Port settings differ from requested: {}", mismatches.join(", "))
+            }
+            DeviceError::BreakUnsupported(e) => {
+                write!(f, "Port or driver can't send a break: {}", e)
+            }
+            DeviceError::PossiblyTruncated(partial) => {
+                write!(f, "Response ended without a line ending, possibly truncated: {:?}", partial)
+            }
+            DeviceError::Timeout => write!(f, "Timed out waiting for a response"),
+            DeviceError::Io(e) => write!(f, "Serial port error: {}", e),
+            DeviceError::Utf8(e) => write!(f, "Response is not valid UTF-8: {}", e),
+            DeviceError::Json(e) => write!(f, "Invalid parameter JSON: {}", e),
+            DeviceError::Parse(val) => write!(f, "Unable to parse response: {:?}", val),
+        }
+    }
+}
+
+impl DeviceError {
+    ///Response bytes exactly as received, for errors raised while parsing a response.
+    pub fn raw(&self) -> Option<&[u8]> {
+        match self {
+            DeviceError::ChecksumMismatch { raw, .. } | DeviceError::MalformedResponse { raw, .. } => {
+                Some(raw)
+            }
+            _ => None,
+        }
+    }
+
+    //Parsers only see the normalized text, the caller holding the raw frame attaches it
+    pub(crate) fn with_raw(mut self, bytes: &[u8]) -> Self {
+        if let DeviceError::ChecksumMismatch { raw, .. } | DeviceError::MalformedResponse { raw, .. } =
+            &mut self
+        {
+            *raw = bytes.to_vec();
+        }
+        self
+    }
+}
+
+impl std::error::Error for DeviceError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DeviceError::Io(e) => Some(e),
+            DeviceError::BreakUnsupported(e) => Some(e),
+            DeviceError::Utf8(e) => Some(e),
+            DeviceError::Json(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<serialport::Error> for DeviceError {
+    fn from(e: serialport::Error) -> Self {
+        DeviceError::Io(e)
+    }
+}
+
+impl From<io::Error> for DeviceError {
+    fn from(e: io::Error) -> Self {
+        DeviceError::Io(e.into())
+    }
+}
+
+impl From<str::Utf8Error> for DeviceError {
+    fn from(e: str::Utf8Error) -> Self {
+        DeviceError::Utf8(e)
+    }
+}
+
+impl From<serde_json::Error> for DeviceError {
+    fn from(e: serde_json::Error) -> Self {
+        DeviceError::Json(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn io_errors_convert_and_keep_their_source() {
+        let err = DeviceError::from(io::Error::from(io::ErrorKind::BrokenPipe));
+        assert!(matches!(err, DeviceError::Io(_)));
+        assert!(err.source().is_some());
+        assert!(err.to_string().starts_with("Serial port error"));
+    }
+
+    #[test]
+    fn checksum_error_has_no_source() {
+        let err = DeviceError::ChecksumMismatch {
+            expected: 0x8F3A,
+            received: String::from("0000"),
+            raw: Vec::new(),
+        };
+        assert_eq!(err.to_string(), "Invalid Checksum: expected 8F3A but received \"0000\"");
+        assert!(err.source().is_none());
+    }
+}
@@ -1,3 +1,12 @@
+#[cfg(feature = "async")]
+pub mod async_device;
+pub mod checksum;
+pub mod command;
+pub mod error;
+#[cfg(test)]
+mod mock_port;
 pub mod my_device;
+#[cfg(feature = "net")]
+pub mod net;
 pub mod parameters;
 pub mod serial_device;
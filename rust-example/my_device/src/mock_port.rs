@@ -0,0 +1,331 @@
+//Scripted stand-in for a serial port, so MyDevice can be tested without hardware.
+//Each command's reply is queued up front and handed out when the command is flushed.
+
+use std::collections::{BTreeSet, VecDeque};
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+
+use crate::checksum::crc16_with_terminator;
+
+//Names held by open_exclusive ports, the OS only lets one handle claim a port
+static CLAIMED: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+pub(crate) struct MockState {
+    pub replies: VecDeque<Vec<Vec<u8>>>, //chunks queued for reading after each command
+    pub input: VecDeque<Vec<u8>>, //chunks waiting to be read, at most one per read
+    pub unflushed: Vec<u8>, //written since the last flush
+    pub max_write: usize, //bytes accepted per write, like a driver with a small buffer
+    pub writes: Vec<Vec<u8>>, //one entry per flushed command
+    pub reads: usize,
+    pub clears: usize,
+    pub flushes: usize,
+    pub fail_reads: usize, //reads that fail with a broken pipe before any input is served
+    pub read_delay: Duration, //how long each read takes, to fake a slow device
+    pub timeout: Duration,
+    pub baud_rate: u32,
+    pub flow_control: FlowControl,
+    pub parity: Parity,
+    pub stop_bits: StopBits,
+    pub dtr: Option<bool>,
+    pub rts: Option<bool>,
+    pub cts: bool,
+    pub dsr: bool,
+    pub cd: bool,
+    pub ri: bool,
+    pub break_unsupported: bool,
+    pub breaks: usize,
+    pub in_break: bool,
+}
+
+impl Default for MockState {
+    fn default() -> Self {
+        MockState {
+            replies: VecDeque::new(),
+            input: VecDeque::new(),
+            unflushed: Vec::new(),
+            max_write: usize::MAX,
+            writes: Vec::new(),
+            reads: 0,
+            clears: 0,
+            flushes: 0,
+            fail_reads: 0,
+            read_delay: Duration::ZERO,
+            timeout: Duration::from_millis(5000),
+            baud_rate: 115_200,
+            flow_control: FlowControl::None,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            dtr: None,
+            rts: None,
+            cts: false,
+            dsr: false,
+            cd: false,
+            ri: false,
+            break_unsupported: false,
+            breaks: 0,
+            in_break: false,
+        }
+    }
+}
+
+//Releases an exclusive claim when the handle that opened the port is dropped
+struct Claim(String);
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        CLAIMED.lock().unwrap_or_else(PoisonError::into_inner).remove(&self.0);
+    }
+}
+
+///Clones share the same script and record, so a test can keep a handle after giving
+///the port to a device.
+pub(crate) struct MockPort {
+    name: Option<String>,
+    state: Arc<Mutex<MockState>>,
+    _claim: Option<Claim>, //held until this handle is dropped
+}
+
+//An exclusive claim stays with the handle that opened the port, a clone kept by the
+//test doesn't hold the port open
+impl Clone for MockPort {
+    fn clone(&self) -> Self {
+        MockPort {
+            name: self.name.clone(),
+            state: self.state.clone(),
+            _claim: None,
+        }
+    }
+}
+
+impl MockPort {
+    pub fn new() -> Self {
+        MockPort {
+            name: Some(String::from("MOCK")),
+            state: Arc::new(Mutex::new(MockState::default())),
+            _claim: None,
+        }
+    }
+
+    ///Opens `name` the way the OS does, failing while another handle still has it.
+    pub fn open_exclusive(name: &str) -> serialport::Result<MockPort> {
+        if !CLAIMED.lock().unwrap_or_else(PoisonError::into_inner).insert(String::from(name)) {
+            return Err(serialport::Error::new(
+                serialport::ErrorKind::NoDevice,
+                format!("{} is already open", name),
+            ));
+        }
+        Ok(MockPort {
+            name: Some(String::from(name)),
+            _claim: Some(Claim(String::from(name))),
+            ..MockPort::new()
+        })
+    }
+
+    pub fn state(&self) -> MutexGuard<'_, MockState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    ///Queues the reply to the next command, read back one chunk per read.
+    pub fn reply(&self, chunks: &[&[u8]]) -> &Self {
+        let chunks = chunks.iter().map(|chunk| chunk.to_vec()).collect();
+        self.state().replies.push_back(chunks);
+        self
+    }
+
+    ///Bytes that are already waiting to be read before any command is sent.
+    pub fn push_input(&self, chunk: &[u8]) {
+        self.state().input.push_back(chunk.to_vec());
+    }
+
+    ///Every command flushed to the port so far.
+    pub fn writes(&self) -> Vec<Vec<u8>> {
+        self.state().writes.clone()
+    }
+}
+
+///Data line with a valid checksum, i.e. "GET,LED_DRIVE,0,40;8F3A\n"
+pub(crate) fn data_line(msg: &str) -> Vec<u8> {
+    format!("{};{:04X}\n", msg, crc16_with_terminator(msg)).into_bytes()
+}
+
+impl Read for MockPort {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let delay = self.state().read_delay;
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+        let mut state = self.state();
+        state.reads += 1;
+        if state.fail_reads > 0 {
+            state.fail_reads -= 1;
+            return Err(io::Error::from(io::ErrorKind::BrokenPipe));
+        }
+        //nothing left stands in for the port timeout running out, without the wait
+        let mut chunk = match state.input.pop_front() {
+            Some(chunk) => chunk,
+            None => return Err(io::Error::from(io::ErrorKind::TimedOut)),
+        };
+        if chunk.len() > buf.len() {
+            let rest = chunk.split_off(buf.len());
+            state.input.push_front(rest);
+        }
+        buf[..chunk.len()].copy_from_slice(&chunk);
+        Ok(chunk.len())
+    }
+}
+
+impl Write for MockPort {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state();
+        let n = buf.len().min(state.max_write);
+        state.unflushed.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    //A flush ends a command, the device "answers" with the next queued reply
+    fn flush(&mut self) -> io::Result<()> {
+        let mut state = self.state();
+        state.flushes += 1;
+        if state.unflushed.is_empty() {
+            return Ok(());
+        }
+        let cmd = std::mem::take(&mut state.unflushed);
+        state.writes.push(cmd);
+        if let Some(reply) = state.replies.pop_front() {
+            state.input.extend(reply);
+        }
+        Ok(())
+    }
+}
+
+impl SerialPort for MockPort {
+    fn name(&self) -> Option<String> {
+        self.name.clone()
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(self.state().baud_rate)
+    }
+
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        Ok(DataBits::Eight)
+    }
+
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        Ok(self.state().flow_control)
+    }
+
+    fn parity(&self) -> serialport::Result<Parity> {
+        Ok(self.state().parity)
+    }
+
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        Ok(self.state().stop_bits)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.state().timeout
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        self.state().baud_rate = baud_rate;
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, _data_bits: DataBits) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn set_flow_control(&mut self, flow_control: FlowControl) -> serialport::Result<()> {
+        self.state().flow_control = flow_control;
+        Ok(())
+    }
+
+    fn set_parity(&mut self, parity: Parity) -> serialport::Result<()> {
+        self.state().parity = parity;
+        Ok(())
+    }
+
+    fn set_stop_bits(&mut self, stop_bits: StopBits) -> serialport::Result<()> {
+        self.state().stop_bits = stop_bits;
+        Ok(())
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        self.state().timeout = timeout;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, level: bool) -> serialport::Result<()> {
+        self.state().rts = Some(level);
+        Ok(())
+    }
+
+    fn write_data_terminal_ready(&mut self, level: bool) -> serialport::Result<()> {
+        self.state().dtr = Some(level);
+        Ok(())
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Ok(self.state().cts)
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Ok(self.state().dsr)
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Ok(self.state().ri)
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Ok(self.state().cd)
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        let queued: usize = self.state().input.iter().map(Vec::len).sum();
+        Ok(queued as u32)
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(self.state().unflushed.len() as u32)
+    }
+
+    fn clear(&self, buffer_to_clear: ClearBuffer) -> serialport::Result<()> {
+        let mut state = self.state();
+        state.clears += 1;
+        if matches!(buffer_to_clear, ClearBuffer::Input | ClearBuffer::All) {
+            state.input.clear();
+        }
+        if matches!(buffer_to_clear, ClearBuffer::Output | ClearBuffer::All) {
+            state.unflushed.clear();
+        }
+        Ok(())
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Ok(Box::new(self.clone()))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        let mut state = self.state();
+        if state.break_unsupported {
+            return Err(serialport::Error::new(
+                serialport::ErrorKind::Unknown,
+                "break not supported",
+            ));
+        }
+        state.breaks += 1;
+        state.in_break = true;
+        Ok(())
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        self.state().in_break = false;
+        Ok(())
+    }
+}
@@ -1,160 +1,2536 @@
 // use std::fs::OpenOptions;
-use std::str;
-use std::time::Duration;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::str::{self, FromStr};
+use std::thread;
+use std::time::{Duration, Instant};
 use std::sync::Mutex;
 
 // use dfu::core::Dfu; -- not supported on Windows...
 // use rfd::FileDialog; //use to pick .dfu files
-use serialport::*;
+use serialport::{
+    ClearBuffer, FlowControl, Parity, SerialPort, SerialPortInfo, SerialPortType, StopBits,
+};
 
+use crate::checksum::checksum_with_terminator;
+use crate::command::{Command, DEFAULT_SAVE_COMMAND};
+use crate::error::DeviceError;
 use crate::parameters;
 use crate::serial_device::SerialDevice;
-use parameters::Parameter;
+use parameters::{ParamType, ParamValue, Parameter, DEFAULT_CAL_SLOPE_RANGE};
 
-pub struct MyDevice {
-    path: String, //OS Path i.e. COM15(windows)
-    port: Box<dyn SerialPort>, //Serialport instance
-    // TODO: store NV_PARAMs line serial number etc..
+pub(crate) const DEFAULT_TIMEOUT: Duration = Duration::from_millis(5000);
+pub(crate) const DEFAULT_READ_BUFFER_SIZE: usize = 32;
+const DEFAULT_RETRY_ATTEMPTS: usize = 1;
+const DATA_FIELD: usize = 3; //index of the value in a data line
+const CALIBRATION_FACTOR: u32 = 4; //calibrated timeout as a multiple of the slowest reply
+const MIN_CALIBRATED_TIMEOUT: Duration = Duration::from_millis(100);
+
+///Framing characters used by the device firmware
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Protocol {
+    pub terminator: char, //ends the data section, followed by the checksum
+    pub separator: char, //between fields in the data section
+    pub save_command: String, //stores NV params, varies between firmware builds
 }
 
-fn crc_16_msb(b: u8, crc: i32) -> i32 {
-    let mut data: i32 = b.into();
-    let mut crc: i32 = crc.into();
-    data <<= 8;
-    for _i in 0..8 {
-        if ((data ^ crc) & 0x8000) != 0 {
-            crc = (0xFFFF) & ((crc << 1) ^ 0x8005);
-        } else {
-            crc = (0xFFFF) & (crc << 1);
+impl Default for Protocol {
+    fn default() -> Self {
+        Protocol {
+            terminator: ';',
+            separator: ',',
+            save_command: String::from(DEFAULT_SAVE_COMMAND),
         }
-        data <<= 1;
     }
-    crc
 }
 
-fn compute_checksum(val: &str) -> i32 {
-    let mut calc = 0;
-    let tail: &str = ";";
-    let mut msg = String::from(val);
-    msg.push_str(tail);
-    let buffer: Vec<u8> = msg.into_bytes();
-    let b_iter = buffer.into_iter();
-    for i in b_iter {
-        calc = crc_16_msb(i, calc);
+impl Protocol {
+    //The terminator is included in the checksum
+    fn checksum(&self, msg: &str) -> u16 {
+        self.checksum_bytes(msg.as_bytes())
+    }
+
+    fn checksum_bytes(&self, msg: &[u8]) -> u16 {
+        checksum_with_terminator(msg, self.terminator)
+    }
+
+    //Outgoing command in the same shape as a data line: the command, the terminator and
+    //the checksum as 4 hex nibbles i.e. "LED,1;8F3A"
+    fn frame(&self, cmd: &[u8]) -> Vec<u8> {
+        let mut framed = cmd.to_vec();
+        framed.extend_from_slice(self.terminator.encode_utf8(&mut [0; 4]).as_bytes());
+        framed.extend_from_slice(format!("{:04X}", self.checksum_bytes(cmd)).as_bytes());
+        framed
     }
+}
 
-    calc
+///How the firmware ends response lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LineEnding {
+    ///Lines end at '\n' and surrounding whitespace, including any '\r', is trimmed.
+    #[default]
+    Lf,
+    ///Lines end at "\r\n", '\r' or '\n'. Only the line ending is removed, so leading
+    ///and trailing spaces are kept.
+    Any,
 }
 
-fn checksum_is_valid(msg: &str, checksum: String) -> bool {
-    if checksum.len() != 4 {
-        return false;
+impl LineEnding {
+    //Index of the first line ending in `buff` and its length in bytes
+    fn find(&self, buff: &[u8]) -> Option<(usize, usize)> {
+        match self {
+            LineEnding::Lf => buff.iter().position(|&b| b == b'\n').map(|end| (end, 1)),
+            LineEnding::Any => {
+                let end = buff.iter().position(|&b| b == b'\r' || b == b'\n')?;
+                let len = if buff[end..].starts_with(b"\r\n") { 2 } else { 1 };
+                Some((end, len))
+            }
+        }
+    }
+
+    //A line with its ending already removed
+    fn strip<'a>(&self, line: &'a [u8]) -> &'a [u8] {
+        match self {
+            LineEnding::Lf => line.trim_ascii(),
+            LineEnding::Any => line,
+        }
     }
-    let msg_check = compute_checksum(msg).to_be_bytes();
-    let check_val = checksum.as_bytes();
-    check_val == msg_check
 }
 
-impl SerialDevice for MyDevice {
-    type Device = TrakPod;
-    fn open(path: &str) -> Mutex<Self::Device> {
-        let port = serialport::new(path, 115_200)
-            .flow_control(FlowControl::None)
-            .timeout(Duration::from_millis(5000))
+//Cuts complete lines off the front of a read buffer. Shared by the blocking and async
+//devices so both frame responses the same way.
+#[derive(Debug, Default)]
+pub(crate) struct LineSplitter {
+    line_ending: LineEnding,
+    pending_lf: bool, //last line ended in a lone '\r', a '\n' straight after belongs to it
+}
+
+impl LineSplitter {
+    pub(crate) fn new(line_ending: LineEnding) -> Self {
+        LineSplitter {
+            line_ending,
+            pending_lf: false,
+        }
+    }
+
+    pub(crate) fn set_line_ending(&mut self, line_ending: LineEnding) {
+        *self = LineSplitter::new(line_ending);
+    }
+
+    //Forget a half-seen "\r\n", i.e. on a fresh port handle
+    pub(crate) fn reset(&mut self) {
+        self.pending_lf = false;
+    }
+
+    //Removes the next complete line from `pending`, returning it as received along with
+    //its text normalized for `line_ending`
+    fn take_line(&mut self, pending: &mut Vec<u8>) -> Option<(Vec<u8>, Vec<u8>)> {
+        if self.pending_lf && pending.first() == Some(&b'\n') {
+            pending.remove(0);
+        }
+        if !pending.is_empty() {
+            self.pending_lf = false;
+        }
+        let (end, len) = self.line_ending.find(pending)?;
+        self.pending_lf = len == 1 && pending[end] == b'\r';
+        let raw: Vec<u8> = pending.drain(..end + len).collect();
+        let text = self.line_ending.strip(&raw[..end]).to_vec();
+        Some((raw, text))
+    }
+
+    //take_line as a Frame, garbled text is a DeviceError::Utf8
+    pub(crate) fn take_frame(&mut self, pending: &mut Vec<u8>) -> Result<Option<Frame>, DeviceError> {
+        match self.take_line(pending) {
+            Some((raw, text)) => {
+                let text = String::from_utf8(text).map_err(|e| e.utf8_error())?;
+                Ok(Some(Frame { raw, text }))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+//Error for a read that ran out of time before a line ending. Nothing at all is a
+//DeviceError::Timeout, part of a line is DeviceError::PossiblyTruncated since the rest
+//of it may still have been on the way.
+pub(crate) fn unterminated(pending: &[u8]) -> DeviceError {
+    if pending.is_empty() {
+        return DeviceError::Timeout;
+    }
+    DeviceError::PossiblyTruncated(String::from_utf8_lossy(strip_nul_padding(pending)).into_owned())
+}
+
+///Which way traced bytes were going
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Tx,
+    Rx,
+}
+
+///Callback for `MyDevice::set_trace`
+pub type TraceFn = Box<dyn Fn(Direction, &[u8]) + Send>;
+
+//Opens a fresh handle for `reopen`
+type OpenPort = fn(&PortSettings, &str) -> serialport::Result<Box<dyn SerialPort>>;
+
+///Commands sent in a captured trace, in order, ready for `MyDevice::replay`.
+///Captured with `checksum_outgoing` set they already carry their checksum, so
+///replay them on a device without it.
+pub fn commands_from_trace(trace: &[(Direction, Vec<u8>)]) -> Vec<Vec<u8>> {
+    trace
+        .iter()
+        .filter(|(direction, _)| *direction == Direction::Tx)
+        .map(|(_, bytes)| bytes.clone())
+        .collect()
+}
+
+//Serial settings a device was opened with, kept so the port can be re-opened the same way
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct PortSettings {
+    baud_rate: u32,
+    timeout: Duration,
+    flow_control: FlowControl,
+    parity: Parity,
+    stop_bits: StopBits,
+}
+
+impl PortSettings {
+    fn open(&self, path: &str) -> serialport::Result<Box<dyn SerialPort>> {
+        serialport::new(path, self.baud_rate)
+            .flow_control(self.flow_control)
+            .parity(self.parity)
+            .stop_bits(self.stop_bits)
+            .timeout(self.timeout)
             .open()
-            .expect("Unable to open port device");
+    }
+}
+
+///Collects construction options for a MyDevice. Defaults match `MyDevice::open`.
+#[derive(Debug, Clone)]
+pub struct MyDeviceBuilder {
+    settings: PortSettings,
+    read_buffer_size: usize,
+    flush_before_command: bool,
+    checksum_outgoing: bool,
+    echo: bool,
+    command_delay: Duration,
+    protocol: Protocol,
+    reset_on_drop: bool,
+    cache_params: bool,
+    line_ending: LineEnding,
+    cal_slope_range: (f32, f32),
+}
+
+impl Default for MyDeviceBuilder {
+    fn default() -> Self {
+        MyDeviceBuilder {
+            settings: PortSettings {
+                baud_rate: MyDevice::BAUD_RATE,
+                timeout: DEFAULT_TIMEOUT,
+                flow_control: FlowControl::None,
+                parity: Parity::None,
+                stop_bits: StopBits::One,
+            },
+            read_buffer_size: DEFAULT_READ_BUFFER_SIZE,
+            flush_before_command: true,
+            checksum_outgoing: false,
+            echo: false,
+            command_delay: Duration::ZERO,
+            protocol: Protocol::default(),
+            reset_on_drop: false,
+            cache_params: false,
+            line_ending: LineEnding::default(),
+            cal_slope_range: DEFAULT_CAL_SLOPE_RANGE,
+        }
+    }
+}
+
+impl MyDeviceBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn baud(mut self, baud_rate: u32) -> Self {
+        self.settings.baud_rate = baud_rate;
+        self
+    }
+
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.settings.timeout = timeout;
+        self
+    }
+
+    ///Defaults to `FlowControl::None`. Adapters that drop bytes on long transfers
+    ///may need `FlowControl::Hardware` (RTS/CTS).
+    pub fn flow_control(mut self, flow_control: FlowControl) -> Self {
+        self.settings.flow_control = flow_control;
+        self
+    }
+
+    ///Defaults to `Parity::None`, the RS-485 variant uses `Parity::Even`.
+    pub fn parity(mut self, parity: Parity) -> Self {
+        self.settings.parity = parity;
+        self
+    }
+
+    ///Defaults to `StopBits::One`.
+    pub fn stop_bits(mut self, stop_bits: StopBits) -> Self {
+        self.settings.stop_bits = stop_bits;
+        self
+    }
+
+    pub fn read_buffer_size(mut self, size: usize) -> Self {
+        self.read_buffer_size = size;
+        self
+    }
 
-        Mutex::new(TrakPod {
+    pub fn flush_before_command(mut self, flush: bool) -> Self {
+        self.flush_before_command = flush;
+        self
+    }
+
+    ///Append a checksum to every command, for firmware that rejects commands without one.
+    pub fn checksum_outgoing(mut self, checksum: bool) -> Self {
+        self.checksum_outgoing = checksum;
+        self
+    }
+
+    ///For devices that echo each command back before the response, discards the echo.
+    pub fn echo(mut self, echo: bool) -> Self {
+        self.echo = echo;
+        self
+    }
+
+    ///Pause before writing each command, for slow firmware that drops the first bytes of
+    ///a command sent straight after the previous response. Defaults to zero.
+    pub fn command_delay(mut self, delay: Duration) -> Self {
+        self.command_delay = delay;
+        self
+    }
+
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    ///Turn the LED off when the device is dropped, see `MyDevice::close`.
+    pub fn reset_on_drop(mut self, reset: bool) -> Self {
+        self.reset_on_drop = reset;
+        self
+    }
+
+    ///Serve repeated `get_param` reads from a cache, see `MyDevice::invalidate_param`.
+    pub fn cache_params(mut self, cache: bool) -> Self {
+        self.cache_params = cache;
+        self
+    }
+
+    pub fn line_ending(mut self, line_ending: LineEnding) -> Self {
+        self.line_ending = line_ending;
+        self
+    }
+
+    ///Inclusive range of slopes CAL_SLOPE accepts, defaults to `DEFAULT_CAL_SLOPE_RANGE`.
+    ///Widen it for sensors with a bigger spread.
+    pub fn cal_slope_range(mut self, min: f32, max: f32) -> Self {
+        self.cal_slope_range = (min, max);
+        self
+    }
+
+    ///Opens the port at `path` with the collected options.
+    pub fn open(self, path: &str) -> Result<MyDevice, DeviceError> {
+        let port = self.settings.open(path)?;
+        Ok(self.with_port(path, port))
+    }
+
+    ///Builds a device around an already open port, i.e. a scripted fake for testing.
+    ///Port settings are only applied if the device has to re-open `path`.
+    pub fn with_port(self, path: &str, port: Box<dyn SerialPort>) -> MyDevice {
+        MyDevice {
             path: String::from(path),
-            port,
+            port: Some(port),
+            open_port: PortSettings::open,
+            settings: self.settings,
+            read_buffer_size: self.read_buffer_size,
+            retry_attempts: DEFAULT_RETRY_ATTEMPTS,
+            flush_before_command: self.flush_before_command,
+            checksum_outgoing: self.checksum_outgoing,
+            echo: self.echo,
+            command_delay: self.command_delay,
+            protocol: self.protocol,
+            serial_number: None,
+            firmware_version: None,
+            reset_on_drop: self.reset_on_drop,
+            last_latency: None,
+            cache_params: self.cache_params,
+            param_cache: HashMap::new(),
+            lines: LineSplitter::new(self.line_ending),
+            cal_slope_range: self.cal_slope_range,
+            trace: None,
+        }
+    }
+}
+
+pub struct MyDevice {
+    path: String, //OS Path i.e. COM15(windows)
+    port: Option<Box<dyn SerialPort>>, //Serialport instance, None after a failed reopen
+    open_port: OpenPort, //how reopen gets a fresh handle, PortSettings::open outside tests
+    settings: PortSettings, //what the port was opened with
+    read_buffer_size: usize, //bytes requested per port read
+    retry_attempts: usize, //tries per command before an IO error is returned
+    flush_before_command: bool, //discard stale input before each command
+    checksum_outgoing: bool, //send commands framed with a checksum, see Protocol::frame
+    echo: bool, //device echoes each command before replying
+    command_delay: Duration, //pause before each write, zero by default
+    protocol: Protocol,
+    serial_number: Option<String>, //cached SER_NUMBER, fetched on first request
+    firmware_version: Option<String>, //cached FW_VERSION, fetched on first request
+    reset_on_drop: bool, //close() when dropped, off by default
+    last_latency: Option<Duration>, //write to end of read for the last command
+    cache_params: bool, //serve get_param from param_cache, off by default
+    param_cache: HashMap<String, String>, //keyed by wire name
+    lines: LineSplitter, //cuts responses into lines by the configured LineEnding
+    cal_slope_range: (f32, f32), //accepted CAL_SLOPE values, inclusive
+    trace: Option<TraceFn>,
+    // TODO: store remaining NV_PARAMs
+}
+
+//Device sends the checksum as 4 hex nibbles i.e. "8F3A".
+//A mismatch carries both sides so noisy lines can be debugged in the field.
+fn verify_checksum(msg: &str, checksum: &str, protocol: &Protocol) -> Result<(), DeviceError> {
+    let expected = protocol.checksum(msg);
+    let received = match checksum.len() {
+        4 => u16::from_str_radix(checksum, 16).ok(),
+        _ => None,
+    };
+    if received == Some(expected) {
+        Ok(())
+    } else {
+        Err(DeviceError::ChecksumMismatch {
+            expected,
+            received: String::from(checksum),
+            raw: Vec::new(),
+        })
+    }
+}
+
+//Access is checked before anything is sent to the device
+fn check_readable(param: Parameter) -> Result<(), DeviceError> {
+    if param.access().is_readable() {
+        Ok(())
+    } else {
+        Err(DeviceError::WriteOnlyParameter(param.as_string()))
+    }
+}
+
+fn check_writable(param: Parameter) -> Result<(), DeviceError> {
+    if param.access().is_writable() {
+        Ok(())
+    } else {
+        Err(DeviceError::ReadOnlyParameter(param.as_string()))
+    }
+}
+
+//Whether the value a device replied with to a SET is the one requested. Floats compare
+//as numbers since the firmware formats them its own way, i.e. "1.050" for "1.05".
+fn applied_matches(param: Parameter, applied: &str, requested: &str) -> bool {
+    match param.value_type() {
+        ParamType::Float => param
+            .parse_value(applied)
+            .is_some_and(|applied| param.parse_value(requested) == Some(applied)),
+        ParamType::Int | ParamType::Text => applied.trim() == requested,
+    }
+}
+
+//Error for commands sent while reopen has left the device without a port
+fn port_closed() -> serialport::Error {
+    serialport::Error::new(serialport::ErrorKind::NoDevice, "port closed after a failed reopen")
+}
+
+//One response line, both as it came off the wire and as text for the parsers
+pub(crate) struct Frame {
+    pub(crate) raw: Vec<u8>,
+    pub(crate) text: String,
+}
+
+//Drops zero padding left over from a pre-filled read buffer, trim() doesn't remove NULs
+fn strip_nul_padding(buff: &[u8]) -> &[u8] {
+    let end = buff.iter().rposition(|&b| b != 0).map_or(0, |i| i + 1);
+    &buff[..end]
+}
+
+//Garbled bytes from a noisy line surface as DeviceError::Utf8 rather than a panic
+pub(crate) fn parse_ack(buff: &[u8]) -> Result<String, DeviceError> {
+    Ok(str::from_utf8(strip_nul_padding(buff))?.to_owned())
+}
+
+//Every field of a data line, after its checksum has been checked
+fn parse_fields(buff: &[u8], protocol: &Protocol) -> Result<Vec<String>, DeviceError> {
+    let read_buffer = str::from_utf8(strip_nul_padding(buff))?;
+    let msg: Vec<&str> = read_buffer.split(protocol.terminator).collect();
+    let data = nth_field(&msg, 0)?;
+    verify_checksum(data, nth_field(&msg, 1)?.trim(), protocol)?;
+
+    Ok(data.split(protocol.separator).map(String::from).collect())
+}
+
+//Bounds-checked index into split response fields, a short response is malformed not a panic
+fn nth_field<S: AsRef<str>>(fields: &[S], idx: usize) -> Result<&str, DeviceError> {
+    fields
+        .get(idx)
+        .map(AsRef::as_ref)
+        .ok_or(DeviceError::MalformedResponse {
+            expected_fields: idx + 1,
+            got: fields.len(),
+            raw: Vec::new(),
+        })
+}
+
+pub(crate) fn parse_data(buff: &[u8], protocol: &Protocol) -> Result<String, DeviceError> {
+    let info = parse_fields(buff, protocol)?;
+
+    // returns specific data
+    Ok(String::from(nth_field(&info, DATA_FIELD)?))
+}
+
+///Reply to a command, as told apart by `send_command`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    ///Plain acknowledgement with no checksum section
+    Ack(String),
+    ///Data line with a checksum section after the terminator
+    Data { fields: Vec<String>, checksum_ok: bool },
+}
+
+///Modem control input lines, as read by `MyDevice::modem_status`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModemStatus {
+    pub cts: bool, //clear to send
+    pub dsr: bool, //data set ready
+    pub cd: bool, //carrier detect
+    pub ri: bool, //ring indicator
+}
+
+///Whole data line as returned by `MyDevice::data_call_full`, for diagnostics
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParsedResponse {
+    ///Every field before the terminator, starting with the command echo
+    pub fields: Vec<String>,
+    ///The field `data_call` would return, None if the line is too short to have one
+    pub value: Option<String>,
+    pub checksum_ok: bool,
+}
+
+///Parameter values captured by `MyDevice::snapshot`, keyed by wire name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceSnapshot {
+    params: BTreeMap<String, String>,
+}
+
+impl DeviceSnapshot {
+    pub fn params(&self) -> &BTreeMap<String, String> {
+        &self.params
+    }
+}
+
+//A terminator means a checksummed data line, anything else is an ack
+fn parse_response(buff: &[u8], protocol: &Protocol) -> Result<Response, DeviceError> {
+    let read_buffer = str::from_utf8(strip_nul_padding(buff))?;
+    match read_buffer.split_once(protocol.terminator) {
+        Some((msg, checksum)) => Ok(Response::Data {
+            fields: msg.split(protocol.separator).map(String::from).collect(),
+            checksum_ok: verify_checksum(msg, checksum.trim(), protocol).is_ok(),
+        }),
+        None => Ok(Response::Ack(read_buffer.to_owned())),
+    }
+}
+
+//Unlike parse_data a bad checksum or missing value is reported rather than an error
+fn parse_full(buff: &[u8], protocol: &Protocol) -> Result<ParsedResponse, DeviceError> {
+    let read_buffer = str::from_utf8(strip_nul_padding(buff))?;
+    let msg: Vec<&str> = read_buffer.split(protocol.terminator).collect();
+    let data = nth_field(&msg, 0)?;
+    let checksum_ok = verify_checksum(data, nth_field(&msg, 1)?.trim(), protocol).is_ok();
+    let fields: Vec<String> = data.split(protocol.separator).map(String::from).collect();
+    Ok(ParsedResponse {
+        value: fields.get(DATA_FIELD).cloned(),
+        fields,
+        checksum_ok,
+    })
+}
+
+//Port name of the single port with a matching USB serial and VID/PID
+fn find_port_by_serial(ports: &[SerialPortInfo], serial: &str) -> Result<String, DeviceError> {
+    let matches: Vec<String> = ports
+        .iter()
+        .filter(|info| match &info.port_type {
+            SerialPortType::UsbPort(usb) => {
+                usb.serial_number.as_deref() == Some(serial)
+                    && MyDevice::usb_ids()
+                        .iter()
+                        .any(|&(vid, pid)| usb.vid == vid && usb.pid == pid)
+            }
+            _ => false,
         })
+        .map(|info| info.port_name.clone())
+        .collect();
+
+    match matches.len() {
+        0 => Err(DeviceError::NotFound(String::from(serial))),
+        1 => Ok(matches.into_iter().next().unwrap()),
+        _ => Err(DeviceError::AmbiguousSerial {
+            serial: String::from(serial),
+            ports: matches,
+        }),
+    }
+}
+
+//Puts the port's original timeout back when dropped, even if the command errored
+struct TimeoutGuard<'a> {
+    device: &'a mut MyDevice,
+    original: Duration,
+}
+
+impl Deref for TimeoutGuard<'_> {
+    type Target = MyDevice;
+    fn deref(&self) -> &MyDevice {
+        self.device
+    }
+}
+
+impl DerefMut for TimeoutGuard<'_> {
+    fn deref_mut(&mut self) -> &mut MyDevice {
+        self.device
+    }
+}
+
+impl Drop for TimeoutGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(port) = self.device.port.as_mut() {
+            let _ = port.set_timeout(self.original);
+        }
+    }
+}
+
+impl SerialDevice for MyDevice {
+    type Device = MyDevice;
+    fn open(path: &str) -> Result<Mutex<Self::Device>, DeviceError> {
+        MyDevice::with_protocol(path, Protocol::default())
     }
 
     const VID: u16 = 0x0483;
     const PID: u16 = 0x5740;
+    const BAUD_RATE: u32 = 115_200;
+
+    fn read_serial(device: &mut Self::Device) -> Option<String> {
+        device.serial_number().ok().map(String::from)
+    }
 }
 
 impl MyDevice {
-    //Just acknowledge that command was received
-    fn ack_call(&mut self, cmd: &[u8]) -> String {
-        let _ = &self.port.write(cmd).expect("Write Failed");
-        let mut buff: Vec<u8> = vec![0; 32];
-        let _ = &self.port.read(buff.as_mut_slice()).expect("Failed to Ack");
-        String::from_utf8(buff).unwrap().trim().to_owned()
+    ///Open a device whose firmware uses different framing characters.
+    pub fn with_protocol(path: &str, protocol: Protocol) -> Result<Mutex<Self>, DeviceError> {
+        MyDeviceBuilder::new().protocol(protocol).open(path).map(Mutex::new)
     }
 
-    //Parses Data value returned from the command
-    fn data_call(&mut self, cmd: &[u8]) -> String {
-        let _ = &self.port.write(cmd).expect("Write Failed");
-        let mut buff: Vec<u8> = vec![0; 32];
-        let _ = &self.port.read(buff.as_mut_slice()).expect("Failed to Ack");
+    ///Wrap any SerialPort implementor with default options.
+    pub fn from_port<P: SerialPort + 'static>(path: &str, port: P) -> Self {
+        MyDeviceBuilder::new().with_port(path, Box::new(port))
+    }
 
-        let read_buffer = String::from_utf8(buff).unwrap();
-        let msg: Vec<&str> = read_buffer.split(";").collect();
-        let checksum = String::from(msg[1].trim());
+    ///Open the device with the given USB serial number, whichever port it was assigned.
+    pub fn open_by_serial(serial: &str) -> Result<Mutex<Self>, DeviceError> {
+        let ports = serialport::available_ports()?;
+        let path = find_port_by_serial(&ports, serial)?;
+        Self::open(&path)
+    }
+
+    pub fn builder() -> MyDeviceBuilder {
+        MyDeviceBuilder::new()
+    }
+
+    ///Sets how many bytes are requested from the port per read.
+    pub fn set_read_buffer_size(&mut self, size: usize) {
+        self.read_buffer_size = size;
+    }
+
+    ///Sets how many times ack/data commands are tried before an IO error is returned.
+    pub fn set_retry_attempts(&mut self, attempts: usize) {
+        self.retry_attempts = attempts;
+    }
+
+    ///Sets whether unread input is discarded before sending each command (default true).
+    pub fn set_flush_before_command(&mut self, flush: bool) {
+        self.flush_before_command = flush;
+    }
+
+    ///Sets whether a checksum is appended to each command (default false).
+    pub fn set_checksum_outgoing(&mut self, checksum: bool) {
+        self.checksum_outgoing = checksum;
+    }
+
+    ///Sets whether the echoed command is discarded before reading a response (default false).
+    pub fn set_echo(&mut self, echo: bool) {
+        self.echo = echo;
+    }
+
+    ///Sets the pause before writing each command (default zero).
+    pub fn set_command_delay(&mut self, delay: Duration) {
+        self.command_delay = delay;
+    }
 
-        if !checksum_is_valid(&msg[0], checksum) {
-            return String::from("Invalid Checksum");
+    ///Runs `f` up to `attempts` times, re-opening the port after each IO error.
+    ///Any other error is returned straight away.
+    pub fn with_retry<F, R>(&mut self, attempts: usize, mut f: F) -> Result<R, DeviceError>
+    where
+        F: FnMut(&mut Self) -> Result<R, DeviceError>,
+    {
+        let mut attempt = 1;
+        loop {
+            match f(self) {
+                Err(DeviceError::Io(_) | DeviceError::Timeout) if attempt < attempts => {
+                    attempt += 1;
+                    //a jostled cable invalidates the handle, if the port isn't back yet the next attempt fails too
+                    let _ = self.reopen();
+                }
+                res => return res,
+            }
         }
+    }
 
-        let info: Vec<&str> = msg[0].split(",").collect();
+    ///Replaces the port handle with a fresh one opened on the same path and settings,
+    ///i.e. after an IO error. The serial number and firmware version stay cached but
+    ///cached parameters are dropped. Ports are opened exclusively so the old handle is
+    ///closed first; on failure commands return an IO error until a reopen succeeds.
+    pub fn reopen(&mut self) -> Result<(), DeviceError> {
+        self.port = None;
+        self.port = Some((self.open_port)(&self.settings, &self.path)?);
+        self.lines.reset();
+        self.invalidate_all();
+        Ok(())
+    }
 
-        // returns specific data
-        String::from(info[3])
+    ///Runs a multi-command sequence as one unit.
+    ///
+    ///Holding `&mut self` for the whole closure means no other command can be sent between
+    ///the steps, as long as callers sharing a `Mutex<MyDevice>` keep the lock for the whole
+    ///transaction rather than re-locking per command.
+    pub fn transaction<F, R>(&mut self, f: F) -> Result<R, DeviceError>
+    where
+        F: FnOnce(&mut Self) -> Result<R, DeviceError>,
+    {
+        f(self)
     }
 
-    //Retrieve multiple data lines when the first line matches the expected return header
-    // fn long_Call(&mut self, cmd: &[u8], expected: &str) -> Vec<String> {
-    //     let res = Vec::new();
-    //     &self.port.write(cmd).expect("Write Failed");
-    //     //Push port reads until get expected response
-    //     let mut buff: Vec<u8> = vec![0; 32];
-    //     &self.port.read(buff.as_mut_slice()).expect("Failed to Ack");
-    //
-    //     res
-    // }
+    //The open port, there is none after a failed reopen
+    fn port(&self) -> Result<&dyn SerialPort, DeviceError> {
+        self.port.as_deref().ok_or_else(|| port_closed().into())
+    }
 
-    pub fn led_on(&mut self) -> String {
-        let cmd = "LED,1".as_bytes();
-        self.ack_call(cmd)
+    fn port_mut(&mut self) -> Result<&mut Box<dyn SerialPort>, DeviceError> {
+        self.port.as_mut().ok_or_else(|| port_closed().into())
     }
 
-    pub fn led_off(&mut self) -> String {
-        let cmd = "LED,0".as_bytes();
-        self.ack_call(cmd)
+    //Temporarily swaps the port timeout until the returned guard is dropped
+    fn timeout_guard(&mut self, timeout: Duration) -> Result<TimeoutGuard<'_>, DeviceError> {
+        let original = self.port()?.timeout();
+        self.port_mut()?.set_timeout(timeout)?;
+        Ok(TimeoutGuard { device: self, original })
     }
 
-    pub fn get_param(&mut self, param: Parameter) -> String {
-        let cmd = String::from("GET,") + &param.as_string();
-        let cmd_buff = cmd.as_bytes();
-        self.data_call(cmd_buff)
+    //Late bytes from a previous command would otherwise be read as this command's response
+    fn write_command(&mut self, cmd: &[u8]) -> Result<(), DeviceError> {
+        if !self.command_delay.is_zero() {
+            thread::sleep(self.command_delay);
+        }
+        if self.flush_before_command {
+            self.port()?.clear(ClearBuffer::Input)?;
+        }
+        let cmd = self.outgoing(cmd);
+        //a partial write would send a truncated command, and buffered bytes only go out on flush
+        self.port_mut()?.write_all(&cmd)?;
+        self.port_mut()?.flush()?;
+        if let Some(trace) = &self.trace {
+            trace(Direction::Tx, &cmd);
+        }
+        Ok(())
     }
 
-    //only sets param temporarily
-    fn set_param(&mut self, param: Parameter, value: String) -> String {
-        if param.is_valid(&value) {
-            let cmd = String::from("SET,") + &param.as_string() + &value;
-            let cmd_buff = cmd.as_bytes();
-            self.data_call(cmd_buff)
+    //Bytes that actually go on the wire for `cmd`
+    fn outgoing<'a>(&self, cmd: &'a [u8]) -> Cow<'a, [u8]> {
+        if self.checksum_outgoing {
+            Cow::Owned(self.protocol.frame(cmd))
         } else {
-            String::from("Invalid Parameter")
+            Cow::Borrowed(cmd)
         }
     }
 
-    //Save all set NV parameters
-    fn save_params(&mut self) -> String {
-        let cmd = "CAL,1,1".as_bytes();
-        self.ack_call(cmd)
+    //Every read from the port goes through here so the trace hook sees all received bytes
+    fn read_port(&mut self, buff: &mut [u8]) -> io::Result<usize> {
+        let port = self.port.as_mut().ok_or_else(|| io::Error::from(port_closed()))?;
+        let n = port.read(buff)?;
+        if let Some(trace) = &self.trace {
+            trace(Direction::Rx, &buff[..n]);
+        }
+        Ok(n)
+    }
+
+    ///Calls `trace` with the raw bytes of every write to and read from the port.
+    pub fn set_trace(&mut self, trace: TraceFn) {
+        self.trace = Some(trace);
+    }
+
+    pub fn clear_trace(&mut self) {
+        self.trace = None;
+    }
+
+    //Write a command and read its response, timing the whole exchange
+    fn round_trip(&mut self, cmd: &[u8]) -> Result<Frame, DeviceError> {
+        let start = Instant::now();
+        self.write_command(cmd)?;
+        //the command delay is spent before the write, it isn't part of the exchange
+        let start = start + self.command_delay;
+        let echo = if self.echo { self.outgoing(cmd).into_owned() } else { Vec::new() };
+        let frame = self.read_frame(start + self.port()?.timeout(), &echo)?;
+        self.last_latency = Some(start.elapsed());
+        Ok(frame)
+    }
+
+    ///Reads the settings back from the port and compares them to what was requested.
+    ///Some USB-serial drivers silently ignore settings they don't support.
+    pub fn verify_settings(&self) -> Result<(), DeviceError> {
+        let mut mismatches = Vec::new();
+        let baud_rate = self.port()?.baud_rate()?;
+        if baud_rate != self.settings.baud_rate {
+            mismatches.push(format!(
+                "baud rate {} (requested {})",
+                baud_rate, self.settings.baud_rate
+            ));
+        }
+        let flow_control = self.port()?.flow_control()?;
+        if flow_control != self.settings.flow_control {
+            mismatches.push(format!(
+                "flow control {} (requested {})",
+                flow_control, self.settings.flow_control
+            ));
+        }
+        let parity = self.port()?.parity()?;
+        if parity != self.settings.parity {
+            mismatches.push(format!("parity {} (requested {})", parity, self.settings.parity));
+        }
+        let stop_bits = self.port()?.stop_bits()?;
+        if stop_bits != self.settings.stop_bits {
+            mismatches.push(format!(
+                "stop bits {} (requested {})",
+                stop_bits, self.settings.stop_bits
+            ));
+        }
+        if mismatches.is_empty() {
+            Ok(())
+        } else {
+            Err(DeviceError::SettingsMismatch(mismatches))
+        }
+    }
+
+    ///Times `samples` SER_NUMBER reads and sets the timeout to 4x the slowest, but no less
+    ///than 100ms, so error cases fail fast while slow USB hubs still get enough time.
+    ///Returns the new timeout, which is also used if the port is re-opened.
+    pub fn calibrate_timeout(&mut self, samples: usize) -> Result<Duration, DeviceError> {
+        if samples == 0 {
+            return Err(DeviceError::InvalidParameter);
+        }
+        let mut slowest = Duration::ZERO;
+        for _ in 0..samples {
+            //bypasses the parameter cache, which would answer without a round trip
+            self.execute(Command::GetParam(Parameter::SerNumber))?;
+            slowest = slowest.max(self.last_latency.unwrap_or_default());
+        }
+        let timeout = (slowest * CALIBRATION_FACTOR).max(MIN_CALIBRATED_TIMEOUT);
+        self.port_mut()?.set_timeout(timeout)?;
+        self.settings.timeout = timeout;
+        Ok(timeout)
+    }
+
+    ///How long the last command took from write to a complete response.
+    pub fn last_latency(&self) -> Option<Duration> {
+        self.last_latency
+    }
+
+    //Reads until a line ending arrives or `deadline` passes, so neither a response split
+    //across reads nor a trickle of bytes can hold a command past its timeout. See
+    //`unterminated` for the errors when the deadline passes.
+    //Leading bytes matching `echo` are discarded, even if the reply follows in the same read.
+    fn read_frame(&mut self, deadline: Instant, echo: &[u8]) -> Result<Frame, DeviceError> {
+        self.next_frame(&mut Vec::new(), deadline, echo)
+    }
+
+    //read_frame for one of several lines, bytes past the line ending stay in `pending` for
+    //the next call
+    fn next_frame(
+        &mut self,
+        pending: &mut Vec<u8>,
+        deadline: Instant,
+        mut echo: &[u8],
+    ) -> Result<Frame, DeviceError> {
+        let mut buff: Vec<u8> = vec![0; self.read_buffer_size];
+        //each read only waits for what's left of the deadline, the guard restores the timeout
+        let mut device = self.timeout_guard(deadline.saturating_duration_since(Instant::now()))?;
+        loop {
+            if !echo.is_empty() {
+                let n = echo.len().min(pending.len());
+                if pending[..n] == echo[..n] {
+                    pending.drain(..n);
+                    echo = &echo[n..];
+                } else {
+                    //not an echo after all, read it as the response
+                    echo = &[];
+                }
+            }
+            //until the whole echo has been read the rest of it may still be on the way
+            if echo.is_empty() {
+                if let Some(frame) = device.lines.take_frame(pending)? {
+                    return Ok(frame);
+                }
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                break;
+            }
+            device.port_mut()?.set_timeout(remaining)?;
+            match device.read_port(buff.as_mut_slice()) {
+                Ok(0) => break,
+                Ok(n) => pending.extend_from_slice(&buff[..n]),
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Err(unterminated(pending))
+    }
+
+    //Just acknowledge that command was received
+    fn ack_call(&mut self, cmd: &[u8]) -> Result<String, DeviceError> {
+        let attempts = self.retry_attempts;
+        self.with_retry(attempts, |device| {
+            let frame = device.round_trip(cmd)?;
+            parse_ack(frame.text.as_bytes()).map_err(|e| e.with_raw(&frame.raw))
+        })
+    }
+
+    //Parses Data value returned from the command
+    fn data_call(&mut self, cmd: &[u8]) -> Result<String, DeviceError> {
+        let attempts = self.retry_attempts;
+        self.with_retry(attempts, |device| {
+            let frame = device.round_trip(cmd)?;
+            parse_data(frame.text.as_bytes(), &device.protocol).map_err(|e| e.with_raw(&frame.raw))
+        })
+    }
+
+    //Next line, normalized for `line_ending`. Bytes past the line ending stay in `pending`
+    //so a line split across several reads is put back together.
+    fn read_line(&mut self, pending: &mut Vec<u8>, deadline: Instant) -> Result<String, DeviceError> {
+        Ok(self.next_frame(pending, deadline, &[])?.text)
+    }
+
+    ///Drives the DTR line, some STM32 bootloaders use DTR/RTS to enter DFU
+    ///when the firmware won't respond to `DFU,0`.
+    pub fn set_dtr(&mut self, level: bool) -> Result<(), DeviceError> {
+        self.port_mut()?.write_data_terminal_ready(level)?;
+        Ok(())
+    }
+
+    ///Drives the RTS line.
+    pub fn set_rts(&mut self, level: bool) -> Result<(), DeviceError> {
+        self.port_mut()?.write_request_to_send(level)?;
+        Ok(())
+    }
+
+    ///Reads the modem control inputs, for diagnosing cabling and flow control in the field.
+    ///Takes `&mut self` because serialport reads the lines through a mutable port.
+    pub fn modem_status(&mut self) -> Result<ModemStatus, DeviceError> {
+        Ok(ModemStatus {
+            cts: self.port_mut()?.read_clear_to_send()?,
+            dsr: self.port_mut()?.read_data_set_ready()?,
+            cd: self.port_mut()?.read_carrier_detect()?,
+            ri: self.port_mut()?.read_ring_indicator()?,
+        })
+    }
+
+    ///Holds the line in a break condition for `duration`, which resets the firmware's
+    ///line parser. Fails with `DeviceError::BreakUnsupported` if the driver can't do it.
+    pub fn send_break(&mut self, duration: Duration) -> Result<(), DeviceError> {
+        self.port()?.set_break().map_err(DeviceError::BreakUnsupported)?;
+        thread::sleep(duration);
+        self.port()?.clear_break()?;
+        Ok(())
+    }
+
+    ///Blocks until everything written so far has been sent.
+    pub fn flush(&mut self) -> Result<(), DeviceError> {
+        self.port_mut()?.flush()?;
+        Ok(())
+    }
+
+    ///Writes a command without reading a reply, for fire-and-forget commands like a reset
+    ///that would otherwise sit out the whole read timeout.
+    pub fn send_no_reply(&mut self, cmd: &[u8]) -> Result<(), DeviceError> {
+        self.write_command(cmd)
+    }
+
+    ///Sends arbitrary bytes and returns whatever a single read gets back, unparsed.
+    ///Meant for firmware bring-up and debugging unknown responses.
+    pub fn raw(&mut self, cmd: &[u8]) -> Result<Vec<u8>, DeviceError> {
+        self.write_command(cmd)?;
+        let mut buff: Vec<u8> = vec![0; self.read_buffer_size];
+        let n = self.read_port(buff.as_mut_slice())?;
+        buff.truncate(n);
+        Ok(buff)
+    }
+
+    ///Sends each command through `raw` and collects the replies, for replaying a recorded
+    ///session against new firmware. With `stop_on_error` the first error is the last result.
+    pub fn replay(&mut self, commands: &[Vec<u8>], stop_on_error: bool) -> Vec<Result<String, DeviceError>> {
+        let mut results = Vec::with_capacity(commands.len());
+        for cmd in commands {
+            let result = self
+                .raw(cmd)
+                .and_then(|reply| String::from_utf8(reply).map_err(|e| e.utf8_error().into()));
+            let failed = result.is_err();
+            results.push(result);
+            if failed && stop_on_error {
+                break;
+            }
+        }
+        results
+    }
+
+    ///Sends a command and reports whether the device acked or returned data.
+    ///Useful when firmware doesn't reply with the shape a command normally gets.
+    pub fn send_command(&mut self, cmd: &[u8]) -> Result<Response, DeviceError> {
+        let attempts = self.retry_attempts;
+        self.with_retry(attempts, |device| {
+            let frame = device.round_trip(cmd)?;
+            parse_response(frame.text.as_bytes(), &device.protocol)
+                .map_err(|e| e.with_raw(&frame.raw))
+        })
     }
 
-    //Sets and Saves new parameter value
-    pub fn update_param(&mut self, param: Parameter, value: String) -> String {
-        self.set_param(param, value);
-        self.save_params()
+    ///Sends a command and returns every field of the checksummed reply.
+    pub fn data_call_fields(&mut self, cmd: &[u8]) -> Result<Vec<String>, DeviceError> {
+        let attempts = self.retry_attempts;
+        self.with_retry(attempts, |device| {
+            let frame = device.round_trip(cmd)?;
+            parse_fields(frame.text.as_bytes(), &device.protocol)
+                .map_err(|e| e.with_raw(&frame.raw))
+        })
+    }
+
+    ///Sends a command and returns the whole parsed data line, even if its checksum fails.
+    pub fn data_call_full(&mut self, cmd: &[u8]) -> Result<ParsedResponse, DeviceError> {
+        let attempts = self.retry_attempts;
+        self.with_retry(attempts, |device| {
+            let frame = device.round_trip(cmd)?;
+            parse_full(frame.text.as_bytes(), &device.protocol).map_err(|e| e.with_raw(&frame.raw))
+        })
+    }
+
+    ///Sends a command that replies with several lines after a header, i.e. a parameter table.
+    ///Lines before one starting with `expected` are skipped, the lines after it are collected
+    ///until an empty line or the port timeout, which covers the whole reply.
+    pub fn long_call(&mut self, cmd: &[u8], expected: &str) -> Result<Vec<String>, DeviceError> {
+        let deadline = Instant::now() + self.port()?.timeout();
+        self.write_command(cmd)?;
+        let mut pending: Vec<u8> = Vec::new();
+        while !self.read_line(&mut pending, deadline)?.starts_with(expected) {}
+
+        let mut res = Vec::new();
+        loop {
+            match self.read_line(&mut pending, deadline) {
+                Ok(line) if line.is_empty() => return Ok(res),
+                Ok(line) => res.push(line),
+                Err(DeviceError::Timeout) => return Ok(res),
+                Err(e) => return Err(e),
+            }
+        }
     }
 
-    fn enter_dfu_mode(&mut self) {
-        let cmd = "DFU,0".as_bytes();
-        self.ack_call(cmd);
+    ///Sends a command that always replies with `n` lines and reads exactly that many within
+    ///the port timeout. Otherwise the lines read so far are returned in `DeviceError::Incomplete`.
+    pub fn read_n_lines(&mut self, cmd: &[u8], n: usize) -> Result<Vec<String>, DeviceError> {
+        let deadline = Instant::now() + self.port()?.timeout();
+        self.write_command(cmd)?;
+        let mut pending: Vec<u8> = Vec::new();
+        let mut lines = Vec::with_capacity(n);
+        while lines.len() < n {
+            match self.read_line(&mut pending, deadline) {
+                Ok(line) => lines.push(line),
+                //a partial last line is as incomplete as a missing one
+                Err(DeviceError::Timeout | DeviceError::PossiblyTruncated(_)) => {
+                    return Err(DeviceError::Incomplete { expected: n, lines });
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(lines)
+    }
+
+    ///Sends a command and checks the device replied with the expected ack.
+    pub fn expect_ack(&mut self, cmd: &[u8], expected: &str) -> Result<String, DeviceError> {
+        let ack = self.ack_call(cmd)?;
+        if ack != expected {
+            return Err(DeviceError::UnexpectedAck {
+                expected: String::from(expected),
+                got: ack,
+            });
+        }
+        Ok(ack)
+    }
+
+    ///Sends a command, checking its ack or returning its data value.
+    ///Parameter values aren't validated here, use `set_param_typed` or `update_param` for that.
+    pub fn execute(&mut self, cmd: Command) -> Result<String, DeviceError> {
+        let bytes = cmd.to_bytes(&self.protocol);
+        match cmd.expected_ack() {
+            Some(ack) => self.expect_ack(&bytes, ack),
+            None => self.data_call(&bytes),
+        }
+    }
+
+    pub fn led_on(&mut self) -> Result<String, DeviceError> {
+        self.execute(Command::LedOn)
+    }
+
+    pub fn led_off(&mut self) -> Result<String, DeviceError> {
+        self.execute(Command::LedOff)
+    }
+
+    ///Turns the LED on or off, first setting LED_DRIVE when `drive` is given, i.e.
+    ///`set_led(true, Some(102))` for on at 40%. The drive level isn't saved to NV.
+    pub fn set_led(&mut self, on: bool, drive: Option<u8>) -> Result<(), DeviceError> {
+        if let Some(drive) = drive {
+            self.set_param_typed(Parameter::LedDrive, ParamValue::Int(i32::from(drive)))?;
+        }
+        if on {
+            self.led_on()?;
+        } else {
+            self.led_off()?;
+        }
+        Ok(())
+    }
+
+    pub fn led_on_with_timeout(&mut self, timeout: Duration) -> Result<String, DeviceError> {
+        self.timeout_guard(timeout)?.led_on()
+    }
+
+    pub fn led_off_with_timeout(&mut self, timeout: Duration) -> Result<String, DeviceError> {
+        self.timeout_guard(timeout)?.led_off()
+    }
+
+    pub fn get_param(&mut self, param: Parameter) -> Result<String, DeviceError> {
+        check_readable(param)?;
+        if !self.cache_params {
+            return self.execute(Command::GetParam(param));
+        }
+        if let Some(value) = self.param_cache.get(param.as_str()) {
+            return Ok(value.clone());
+        }
+        let value = self.execute(Command::GetParam(param))?;
+        self.param_cache.insert(param.as_string(), value.clone());
+        Ok(value)
+    }
+
+    pub fn set_line_ending(&mut self, line_ending: LineEnding) {
+        self.lines.set_line_ending(line_ending);
+    }
+
+    ///See `MyDeviceBuilder::cal_slope_range`.
+    pub fn set_cal_slope_range(&mut self, min: f32, max: f32) {
+        self.cal_slope_range = (min, max);
+    }
+
+    //Parameter::is_valid with this device's CAL_SLOPE range
+    fn is_valid(&self, param: Parameter, value: &str) -> bool {
+        let float_range = match param {
+            Parameter::CalSlope => Some(self.cal_slope_range),
+            _ => param.float_range(),
+        };
+        param.is_valid_within(value, float_range)
+    }
+
+    ///Turns parameter caching on or off. Turning it off also empties the cache.
+    pub fn set_cache_params(&mut self, cache: bool) {
+        self.cache_params = cache;
+        if !cache {
+            self.invalidate_all();
+        }
+    }
+
+    ///Forces the next `get_param` for `param` to read from the device.
+    pub fn invalidate_param(&mut self, param: Parameter) {
+        self.param_cache.remove(param.as_str());
+    }
+
+    pub fn invalidate_all(&mut self) {
+        self.param_cache.clear();
+    }
+
+    pub fn get_param_with_timeout(&mut self, param: Parameter, timeout: Duration) -> Result<String, DeviceError> {
+        self.timeout_guard(timeout)?.get_param(param)
+    }
+
+    ///Sets whether the device is put back in a safe state (LED off) when dropped.
+    pub fn set_reset_on_drop(&mut self, reset: bool) {
+        self.reset_on_drop = reset;
+    }
+
+    ///Best-effort return to a known state by turning the LED off. Errors are ignored
+    ///since this also runs from Drop, where they can't be reported.
+    pub fn close(&mut self) {
+        let _ = self.led_off();
+    }
+
+    ///Device serial number, read from the device once and cached.
+    ///A failed read isn't cached so the next call tries again.
+    pub fn serial_number(&mut self) -> Result<&str, DeviceError> {
+        let serial = match self.serial_number.take() {
+            Some(serial) => serial,
+            None => self.get_param(Parameter::SerNumber)?,
+        };
+        Ok(self.serial_number.insert(serial).as_str())
+    }
+
+    ///Firmware version i.e. "1.2.3", read from the device once and cached.
+    ///A reply that isn't a dotted version is a `DeviceError::Parse` and isn't cached.
+    pub fn firmware_version(&mut self) -> Result<String, DeviceError> {
+        if let Some(version) = &self.firmware_version {
+            return Ok(version.clone());
+        }
+        let version = self.get_param(Parameter::FwVersion)?;
+        let version = version.trim();
+        if !Parameter::FwVersion.is_valid(version) {
+            return Err(DeviceError::Parse(String::from(version)));
+        }
+        Ok(self.firmware_version.insert(String::from(version)).clone())
+    }
+
+    ///Reads every readable parameter, keyed by wire name. Stops at the first failed read.
+    pub fn dump_all_params(&mut self) -> Result<BTreeMap<String, String>, DeviceError> {
+        let mut params = BTreeMap::new();
+        for &param in Parameter::all().iter().filter(|param| param.access().is_readable()) {
+            let value = self.get_param(param)?;
+            params.insert(param.as_string(), value);
+        }
+        Ok(params)
+    }
+
+    ///All parameters as a JSON object of wire name to value, for backing up a config.
+    pub fn export_params_json(&mut self) -> Result<String, DeviceError> {
+        let params = self.dump_all_params()?;
+        Ok(serde_json::to_string_pretty(&params)?)
+    }
+
+    ///Restores parameters from `export_params_json` output. Every entry is checked
+    ///before the first write, so an unknown name or invalid value changes nothing.
+    ///Read-only parameters like SER_NUMBER are part of the export but skipped here.
+    pub fn import_params_json(&mut self, json: &str) -> Result<(), DeviceError> {
+        let values: BTreeMap<String, String> = serde_json::from_str(json)?;
+        let mut params = Vec::with_capacity(values.len());
+        for (name, value) in values {
+            let param: Parameter = name.parse().map_err(|_| DeviceError::InvalidParameter)?;
+            if !param.access().is_writable() {
+                continue;
+            }
+            if !self.is_valid(param, &value) {
+                return Err(DeviceError::InvalidParameter);
+            }
+            params.push((param, value));
+        }
+        for (param, value) in params {
+            self.update_param(param, value)?;
+        }
+        Ok(())
+    }
+
+    ///Captures every readable parameter so risky changes can be rolled back with `restore`.
+    pub fn snapshot(&mut self) -> Result<DeviceSnapshot, DeviceError> {
+        Ok(DeviceSnapshot {
+            params: self.dump_all_params()?,
+        })
+    }
+
+    ///Re-applies the writable parameters that differ from `snap` and saves once.
+    ///Nothing is written if the device already matches.
+    pub fn restore(&mut self, snap: &DeviceSnapshot) -> Result<(), DeviceError> {
+        let current = self.dump_all_params()?;
+        let mut changed = Vec::new();
+        for (name, value) in &snap.params {
+            let param: Parameter = name.parse().map_err(|_| DeviceError::InvalidParameter)?;
+            if param.access().is_writable() && current.get(name) != Some(value) {
+                changed.push((param, value.clone()));
+            }
+        }
+        if changed.is_empty() {
+            return Ok(());
+        }
+        self.set_params_batch(&changed)
+    }
+
+    ///Reads a parameter and parses it according to the parameter's value type.
+    pub fn get_param_typed(&mut self, param: Parameter) -> Result<ParamValue, DeviceError> {
+        let value = self.get_param(param)?;
+        param.parse_value(&value).ok_or(DeviceError::Parse(value))
+    }
+
+    ///Reads a parameter and parses it into any `FromStr` type, i.e. `u8` for LED_DRIVE.
+    pub fn get_param_as<P: FromStr>(&mut self, param: Parameter) -> Result<P, DeviceError> {
+        let value = self.get_param(param)?;
+        value.trim().parse().map_err(|_| DeviceError::Parse(value))
+    }
+
+    ///Validates and temporarily sets a typed parameter value.
+    pub fn set_param_typed(&mut self, param: Parameter, value: ParamValue) -> Result<String, DeviceError> {
+        if value.param_type() != param.value_type() {
+            return Err(DeviceError::InvalidParameter);
+        }
+        let value = value.to_string();
+        if !self.is_valid(param, &value) {
+            return Err(DeviceError::InvalidParameter);
+        }
+        self.set_param(param, value)
+    }
+
+    //only sets param temporarily
+    fn set_param(&mut self, param: Parameter, value: String) -> Result<String, DeviceError> {
+        check_writable(param)?;
+        if self.is_valid(param, &value) {
+            let applied = self.execute(Command::SetParam(param, value))?;
+            self.invalidate_param(param);
+            Ok(applied)
+        } else {
+            Err(DeviceError::InvalidParameter)
+        }
+    }
+
+    //Save all set NV parameters
+    fn save_params(&mut self) -> Result<String, DeviceError> {
+        self.execute(Command::SaveParams)
+    }
+
+    //Sets and Saves new parameter value, nothing is saved if the SET isn't acknowledged.
+    //The device replies to a SET with the value it applied.
+    pub fn update_param(&mut self, param: Parameter, value: String) -> Result<String, DeviceError> {
+        self.transaction(|device| {
+            let applied = device.set_param(param, value.clone())?;
+            if !applied_matches(param, &applied, &value) {
+                return Err(DeviceError::Rejected(applied));
+            }
+            device.save_params()
+        })
+    }
+
+    ///Sets several parameters and saves them with a single NV write. Every value is
+    ///validated first, so nothing is sent if any of them is invalid.
+    pub fn set_params_batch(&mut self, values: &[(Parameter, String)]) -> Result<(), DeviceError> {
+        for (param, value) in values {
+            check_writable(*param)?;
+            if !self.is_valid(*param, value) {
+                return Err(DeviceError::InvalidParameter);
+            }
+        }
+        self.transaction(|device| {
+            for (param, value) in values {
+                let applied = device.set_param(*param, value.clone())?;
+                if !applied_matches(*param, &applied, value) {
+                    return Err(DeviceError::Rejected(applied));
+                }
+            }
+            device.save_params()?;
+            Ok(())
+        })
+    }
+
+    //Saving NV params can take seconds, so this is the usual place to give a longer timeout
+    pub fn update_param_with_timeout(&mut self, param: Parameter, value: String, timeout: Duration) -> Result<String, DeviceError> {
+        self.timeout_guard(timeout)?.update_param(param, value)
+    }
+
+    ///Reboots the device into its DFU bootloader.
+    ///
+    ///The device re-enumerates as a DFU device straight away, often before its ack makes
+    ///it out, so the reply isn't waited for. The serial port handle is no longer usable and
+    ///the device is consumed either way.
+    pub fn enter_dfu_mode(mut self) -> Result<(), DeviceError> {
+        //nothing to reset once the device has left its serial firmware
+        self.reset_on_drop = false;
+        self.send_no_reply(&Command::EnterDfu.to_bytes(&self.protocol))
+    }
+
+    ///Like `enter_dfu_mode`, but waits for the device's ack and returns
+    ///`DeviceError::UnexpectedAck` if it isn't the expected one. Only for firmware that
+    ///acks before it re-enumerates, otherwise this waits out the timeout.
+    pub fn enter_dfu_mode_acked(mut self) -> Result<(), DeviceError> {
+        self.reset_on_drop = false;
+        self.execute(Command::EnterDfu).map(|_| ())
+    }
+}
+
+impl Drop for MyDevice {
+    fn drop(&mut self) {
+        if self.reset_on_drop {
+            self.close();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum::crc16_with_terminator;
+    use crate::mock_port::{data_line, MockPort};
+
+    fn mock_device(port: &MockPort) -> MyDevice {
+        MyDevice::from_port("MOCK", port.clone())
+    }
+
+    //Queues a GET reply for each parameter, in order
+    fn reply_params(port: &MockPort, values: &[(Parameter, &str)]) {
+        for (param, value) in values {
+            port.reply(&[&data_line(&format!("GET,{},0,{}", param.as_str(), value))]);
+        }
+    }
+
+    const ALL_PARAMS: [(Parameter, &str); 4] = [
+        (Parameter::SerNumber, "A1B2C3D4E5F6"),
+        (Parameter::LedDrive, "40"),
+        (Parameter::FwVersion, "1.2.3"),
+        (Parameter::CalSlope, "1.05"),
+    ];
+
+    #[test]
+    fn open_missing_port_is_an_error() {
+        assert!(<MyDevice as SerialDevice>::open("/dev/no-such-serial-port").is_err());
+    }
+
+    #[test]
+    fn verify_checksum_accepts_matching_pair() {
+        let protocol = Protocol::default();
+        let msg = "GET,LED_DRIVE,0,40";
+        let checksum = format!("{:04X}", crc16_with_terminator(msg));
+        assert!(verify_checksum(msg, &checksum, &protocol).is_ok());
+    }
+
+    #[test]
+    fn verify_checksum_reports_both_sides_of_a_mismatch() {
+        let protocol = Protocol::default();
+        let msg = "GET,LED_DRIVE,0,40";
+        let expected = crc16_with_terminator(msg);
+        let wrong = format!("{:04X}", expected ^ 1);
+        match verify_checksum(msg, &wrong, &protocol) {
+            Err(DeviceError::ChecksumMismatch { expected: e, received, .. }) => {
+                assert_eq!(e, expected);
+                assert_eq!(received, wrong);
+            }
+            other => panic!("expected ChecksumMismatch, got {:?}", other),
+        }
+        assert!(verify_checksum(msg, "8F3", &protocol).is_err());
+        assert!(verify_checksum(msg, "ZZZZ", &protocol).is_err());
+    }
+
+    fn open_exclusive(_: &PortSettings, path: &str) -> serialport::Result<Box<dyn SerialPort>> {
+        Ok(Box::new(MockPort::open_exclusive(path)?))
+    }
+
+    fn open_fails(_: &PortSettings, _: &str) -> serialport::Result<Box<dyn SerialPort>> {
+        Err(serialport::Error::new(serialport::ErrorKind::NoDevice, "unplugged"))
+    }
+
+    #[test]
+    fn reopen_releases_old_handle_first() {
+        let port = MockPort::open_exclusive("MOCK_REOPEN").unwrap();
+        let old = port.clone();
+        let mut device = MyDevice::from_port("MOCK_REOPEN", port);
+        device.open_port = open_exclusive;
+        device.reopen().unwrap();
+        assert_eq!(device.path, "MOCK_REOPEN");
+        //the command goes to the new handle, which has nothing scripted
+        assert!(matches!(device.led_on(), Err(DeviceError::Timeout)));
+        assert!(old.writes().is_empty());
+    }
+
+    #[test]
+    fn failed_reopen_leaves_device_closed() {
+        let port = MockPort::new();
+        port.reply(&[b"OK\n"]);
+        let mut device = mock_device(&port);
+        device.open_port = open_fails;
+        assert!(device.reopen().is_err());
+        assert!(matches!(device.led_on(), Err(DeviceError::Io(_))));
+        assert!(port.writes().is_empty());
+    }
+
+    #[test]
+    fn update_param_accepts_reformatted_float() {
+        let port = MockPort::new();
+        port.reply(&[&data_line("SET,CAL_SLOPE,0,1.050")]).reply(&[b"OK\n"]);
+        let mut device = mock_device(&port);
+        assert_eq!(device.update_param(Parameter::CalSlope, String::from("1.05")).unwrap(), "OK");
+        assert_eq!(port.writes()[1], DEFAULT_SAVE_COMMAND.as_bytes());
+    }
+
+    #[test]
+    fn update_param_rejects_different_float() {
+        let port = MockPort::new();
+        port.reply(&[&data_line("SET,CAL_SLOPE,0,1.0")]);
+        let mut device = mock_device(&port);
+        let result = device.update_param(Parameter::CalSlope, String::from("1.05"));
+        assert!(matches!(result, Err(DeviceError::Rejected(applied)) if applied == "1.0"));
+        assert_eq!(port.writes().len(), 1);
+    }
+
+    #[test]
+    fn cal_slope_range_is_per_device() {
+        let port = MockPort::new();
+        port.reply(&[&data_line("SET,CAL_SLOPE,0,15")]);
+        let mut device = MyDevice::builder()
+            .cal_slope_range(0.0, 20.0)
+            .with_port("MOCK", Box::new(port.clone()));
+        assert_eq!(device.set_param_typed(Parameter::CalSlope, ParamValue::Float(15.0)).unwrap(), "15");
+        assert!(matches!(
+            device.set_param_typed(Parameter::CalSlope, ParamValue::Float(-1.0)),
+            Err(DeviceError::InvalidParameter)
+        ));
+        assert!(matches!(
+            mock_device(&port).set_param_typed(Parameter::CalSlope, ParamValue::Float(15.0)),
+            Err(DeviceError::InvalidParameter)
+        ));
+    }
+
+    #[test]
+    fn long_call_collects_lines_after_header_across_split_reads() {
+        let port = MockPort::new();
+        port.reply(&[b"stale\nTAB", b"LE\nA,1\nB", b",2\r\nC,3\n", b"\n"]);
+        let mut device = mock_device(&port);
+        let lines = device.long_call(b"TABLE", "TABLE").unwrap();
+        assert_eq!(lines, vec!["A,1", "B,2", "C,3"]);
+    }
+
+    #[test]
+    fn long_call_ends_at_timeout_without_empty_line() {
+        let port = MockPort::new();
+        port.reply(&[b"TABLE\nA,1\n"]);
+        let mut device = mock_device(&port);
+        assert_eq!(device.long_call(b"TABLE", "TABLE").unwrap(), vec!["A,1"]);
+    }
+
+    #[test]
+    fn long_call_rejects_invalid_utf8() {
+        let port = MockPort::new();
+        port.reply(&[b"TABLE\n\xFF\xFE\n"]);
+        let mut device = mock_device(&port);
+        assert!(matches!(device.long_call(b"TABLE", "TABLE"), Err(DeviceError::Utf8(_))));
+    }
+
+    #[test]
+    fn long_call_without_header_times_out() {
+        let port = MockPort::new();
+        port.reply(&[b"A,1\n"]);
+        let mut device = mock_device(&port);
+        assert!(matches!(device.long_call(b"TABLE", "TABLE"), Err(DeviceError::Timeout)));
+    }
+
+    #[test]
+    fn small_read_buffer_reassembles_response() {
+        let port = MockPort::new();
+        let line = data_line("GET,LED_DRIVE,0,40");
+        port.reply(&[&line]);
+        let mut device = mock_device(&port);
+        device.set_read_buffer_size(4);
+        assert_eq!(device.get_param(Parameter::LedDrive).unwrap(), "40");
+        assert_eq!(port.state().reads, line.len().div_ceil(4));
+    }
+
+    #[test]
+    fn get_param_typed_parses_value() {
+        let port = MockPort::new();
+        port.reply(&[&data_line("GET,LED_DRIVE,0,40")]);
+        let mut device = mock_device(&port);
+        assert_eq!(device.get_param_typed(Parameter::LedDrive).unwrap(), ParamValue::Int(40));
+    }
+
+    #[test]
+    fn set_param_typed_rejects_wrong_type_without_writing() {
+        let port = MockPort::new();
+        let mut device = mock_device(&port);
+        let value = ParamValue::Text(String::from("40"));
+        assert!(matches!(
+            device.set_param_typed(Parameter::LedDrive, value),
+            Err(DeviceError::InvalidParameter)
+        ));
+        assert!(port.writes().is_empty());
+    }
+
+    #[test]
+    fn set_param_typed_sends_value() {
+        let port = MockPort::new();
+        port.reply(&[&data_line("SET,LED_DRIVE,0,40")]);
+        let mut device = mock_device(&port);
+        assert_eq!(device.set_param_typed(Parameter::LedDrive, ParamValue::Int(40)).unwrap(), "40");
+        assert_eq!(port.writes().len(), 1);
+    }
+
+    #[test]
+    fn opens_at_115200_baud() {
+        assert_eq!(<MyDevice as SerialDevice>::BAUD_RATE, 115_200);
+    }
+
+    //A sibling unit speaking the same protocol at 9600 baud
+    struct SlowSibling;
+
+    impl SlowSibling {
+        fn builder() -> MyDeviceBuilder {
+            MyDevice::builder().baud(Self::BAUD_RATE)
+        }
+    }
+
+    impl SerialDevice for SlowSibling {
+        type Device = MyDevice;
+        fn open(path: &str) -> Result<Mutex<MyDevice>, DeviceError> {
+            SlowSibling::builder().open(path).map(Mutex::new)
+        }
+
+        const VID: u16 = 0x0483;
+        const PID: u16 = 0x5741;
+        const BAUD_RATE: u32 = 9600;
+    }
+
+    //Stands in for PortSettings::open, a fresh mock configured with the requested settings
+    fn open_with_settings(settings: &PortSettings, _: &str) -> serialport::Result<Box<dyn SerialPort>> {
+        let port = MockPort::new();
+        port.state().baud_rate = settings.baud_rate;
+        Ok(Box::new(port))
+    }
+
+    #[test]
+    fn sibling_device_opens_at_its_own_baud_rate() {
+        assert!(<SlowSibling as SerialDevice>::open("/dev/no-such-serial-port").is_err());
+        let mut device = SlowSibling::builder().with_port("MOCK", Box::new(MockPort::new()));
+        assert_eq!(device.settings.baud_rate, 9600);
+        //the mock starts at 115200, the reopened port gets the sibling's rate
+        assert!(device.verify_settings().is_err());
+        device.open_port = open_with_settings;
+        device.reopen().unwrap();
+        assert_eq!(device.port().unwrap().baud_rate().unwrap(), 9600);
+        device.verify_settings().unwrap();
+    }
+
+    #[test]
+    fn garbled_response_is_a_utf8_error() {
+        assert!(matches!(parse_ack(b"O\xFFK"), Err(DeviceError::Utf8(_))));
+        let port = MockPort::new();
+        port.reply(&[b"\xFE\xFF\n"]);
+        let mut device = mock_device(&port);
+        assert!(matches!(device.led_on(), Err(DeviceError::Utf8(_))));
+    }
+
+    #[test]
+    fn nul_padding_is_stripped_before_parsing() {
+        assert_eq!(strip_nul_padding(b"OK\0\0\0"), b"OK");
+        assert_eq!(strip_nul_padding(b"\0\0"), b"");
+        assert_eq!(parse_ack(b"OK\0\0").unwrap(), "OK");
+        let mut line = data_line("GET,LED_DRIVE,0,40");
+        line.pop();
+        line.extend_from_slice(&[0; 8]);
+        assert_eq!(parse_data(&line, &Protocol::default()).unwrap(), "40");
+    }
+
+    #[test]
+    fn with_retry_retries_io_errors_only() {
+        let port = MockPort::new();
+        let mut device = mock_device(&port);
+        let mut calls = 0;
+        let result = device.with_retry(3, |_| {
+            calls += 1;
+            if calls < 3 {
+                Err(DeviceError::Io(io::Error::from(io::ErrorKind::BrokenPipe).into()))
+            } else {
+                Ok(calls)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        let mut calls = 0;
+        let result: Result<(), DeviceError> = device.with_retry(3, |_| {
+            calls += 1;
+            Err(DeviceError::InvalidParameter)
+        });
+        assert!(matches!(result, Err(DeviceError::InvalidParameter)));
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn with_retry_gives_up_after_attempts() {
+        let port = MockPort::new();
+        let mut device = mock_device(&port);
+        let mut calls = 0;
+        let result: Result<(), DeviceError> = device.with_retry(2, |_| {
+            calls += 1;
+            Err(DeviceError::Io(io::Error::from(io::ErrorKind::TimedOut).into()))
+        });
+        assert!(matches!(result, Err(DeviceError::Io(_))));
+        assert_eq!(calls, 2);
+    }
+
+    //Port handed out by `reopen_shared`, so a test can script what the reopened device replies
+    static REOPENED: Mutex<Option<MockPort>> = Mutex::new(None);
+
+    fn reopen_shared(_: &PortSettings, _: &str) -> serialport::Result<Box<dyn SerialPort>> {
+        match REOPENED.lock().unwrap().clone() {
+            Some(port) => Ok(Box::new(port)),
+            None => Err(serialport::Error::new(serialport::ErrorKind::NoDevice, "unplugged")),
+        }
+    }
+
+    #[test]
+    fn with_retry_reopens_after_a_failed_read() {
+        let port = MockPort::new();
+        *REOPENED.lock().unwrap() = Some(port.clone());
+        let mut device = mock_device(&port);
+        device.open_port = reopen_shared;
+
+        //the first reply is left behind by the failed read and discarded as stale
+        port.state().fail_reads = 1;
+        port.reply(&[b"OK\n"]).reply(&[b"OK\n"]);
+        assert_eq!(device.with_retry(2, |device| device.led_on()).unwrap(), "OK");
+        assert_eq!(port.writes(), vec![b"LED,1".to_vec(), b"LED,1".to_vec()]);
+
+        port.state().fail_reads = 1;
+        reply_params(&port, &[(Parameter::LedDrive, "40"), (Parameter::LedDrive, "40")]);
+        let value = device.with_retry(2, |device| device.get_param(Parameter::LedDrive));
+        assert_eq!(value.unwrap(), "40");
+        assert_eq!(port.writes().len(), 4);
+    }
+
+    #[test]
+    fn command_timeout_is_restored_afterwards() {
+        let port = MockPort::new();
+        port.reply(&[b"OK\n"]);
+        let mut device = mock_device(&port);
+        assert_eq!(device.led_on_with_timeout(Duration::from_millis(50)).unwrap(), "OK");
+        assert_eq!(port.state().timeout, Duration::from_millis(5000));
+        //restored even when the command fails
+        assert!(matches!(device.led_off_with_timeout(Duration::from_millis(50)), Err(DeviceError::Timeout)));
+        assert_eq!(port.state().timeout, Duration::from_millis(5000));
+    }
+
+    #[test]
+    fn update_param_saves_only_the_requested_value() {
+        let port = MockPort::new();
+        port.reply(&[&data_line("SET,LED_DRIVE,0,40")]);
+        port.reply(&[b"OK\n"]);
+        let mut device = mock_device(&port);
+        assert_eq!(device.update_param(Parameter::LedDrive, String::from("40")).unwrap(), "OK");
+        assert_eq!(port.writes().len(), 2);
+
+        let port = MockPort::new();
+        port.reply(&[&data_line("SET,LED_DRIVE,0,39")]);
+        let mut device = mock_device(&port);
+        let result = device.update_param(Parameter::LedDrive, String::from("40"));
+        assert!(matches!(result, Err(DeviceError::Rejected(applied)) if applied == "39"));
+        assert_eq!(port.writes().len(), 1);
+    }
+
+    #[test]
+    fn enter_dfu_mode_sends_dfu_without_waiting_or_resetting() {
+        let port = MockPort::new();
+        let device = MyDevice::builder()
+            .reset_on_drop(true)
+            .with_port("MOCK", Box::new(port.clone()));
+        device.enter_dfu_mode().unwrap();
+        assert_eq!(port.writes(), vec![b"DFU,0".to_vec()]);
+        assert_eq!(port.state().reads, 0);
+    }
+
+    #[test]
+    fn enter_dfu_mode_acked_checks_the_ack() {
+        let port = MockPort::new();
+        port.reply(&[b"OK\n"]);
+        mock_device(&port).enter_dfu_mode_acked().unwrap();
+        assert_eq!(port.writes(), vec![b"DFU,0".to_vec()]);
+
+        let port = MockPort::new();
+        port.reply(&[b"ERR\n"]);
+        match mock_device(&port).enter_dfu_mode_acked() {
+            Err(DeviceError::UnexpectedAck { expected, got }) => {
+                assert_eq!((expected.as_str(), got.as_str()), ("OK", "ERR"));
+            }
+            other => panic!("expected UnexpectedAck, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn dump_all_params_reads_every_parameter() {
+        let port = MockPort::new();
+        reply_params(&port, &ALL_PARAMS);
+        let mut device = mock_device(&port);
+        let params = device.dump_all_params().unwrap();
+        let expected: BTreeMap<String, String> = ALL_PARAMS
+            .iter()
+            .map(|(param, value)| (param.as_string(), String::from(*value)))
+            .collect();
+        assert_eq!(params, expected);
+        assert_eq!(port.writes().len(), Parameter::all().len());
+    }
+
+    #[test]
+    fn stale_input_is_discarded_before_a_command() {
+        let port = MockPort::new();
+        port.push_input(b"STALE\n");
+        port.reply(&[b"OK\n"]);
+        let mut device = mock_device(&port);
+        assert_eq!(device.led_on().unwrap(), "OK");
+        assert_eq!(port.state().clears, 1);
+
+        let port = MockPort::new();
+        port.push_input(b"STALE\n");
+        port.reply(&[b"OK\n"]);
+        let mut device = mock_device(&port);
+        device.set_flush_before_command(false);
+        assert!(matches!(device.led_on(), Err(DeviceError::UnexpectedAck { .. })));
+    }
+
+    #[test]
+    fn custom_terminator_and_separator() {
+        let protocol = Protocol {
+            terminator: '|',
+            separator: ':',
+            ..Protocol::default()
+        };
+        let msg = "GET:LED_DRIVE:0:40";
+        let line = format!("{}|{:04X}\n", msg, protocol.checksum(msg));
+        let port = MockPort::new();
+        port.reply(&[line.as_bytes()]);
+        let mut device = mock_device(&port);
+        device.protocol = protocol.clone();
+        assert_eq!(device.get_param(Parameter::LedDrive).unwrap(), "40");
+    }
+
+    #[test]
+    fn serial_number_is_read_once() {
+        let port = MockPort::new();
+        reply_params(&port, &[(Parameter::SerNumber, "A1B2C3D4E5F6")]);
+        let mut device = mock_device(&port);
+        assert_eq!(device.serial_number().unwrap(), "A1B2C3D4E5F6");
+        assert_eq!(device.serial_number().unwrap(), "A1B2C3D4E5F6");
+        assert_eq!(port.writes().len(), 1);
+    }
+
+    #[test]
+    fn failed_serial_number_read_is_retried() {
+        let port = MockPort::new();
+        port.reply(&[]);
+        reply_params(&port, &[(Parameter::SerNumber, "A1B2C3D4E5F6")]);
+        let mut device = mock_device(&port);
+        assert!(device.serial_number().is_err());
+        assert_eq!(device.serial_number().unwrap(), "A1B2C3D4E5F6");
+    }
+
+    #[test]
+    fn firmware_version_is_parsed_and_cached() {
+        let port = MockPort::new();
+        reply_params(&port, &[(Parameter::FwVersion, "1.2.3")]);
+        let mut device = mock_device(&port);
+        assert_eq!(device.firmware_version().unwrap(), "1.2.3");
+        assert_eq!(device.firmware_version().unwrap(), "1.2.3");
+        assert_eq!(port.writes().len(), 1);
+    }
+
+    #[test]
+    fn firmware_version_rejects_non_version_and_is_not_cached() {
+        let port = MockPort::new();
+        reply_params(&port, &[(Parameter::FwVersion, "abc")]);
+        reply_params(&port, &[(Parameter::FwVersion, "1.2.3")]);
+        let mut device = mock_device(&port);
+        assert!(matches!(device.firmware_version(), Err(DeviceError::Parse(val)) if val == "abc"));
+        assert_eq!(device.firmware_version().unwrap(), "1.2.3");
+        assert_eq!(port.writes().len(), 2);
+    }
+
+    #[test]
+    fn partial_writes_still_send_the_whole_command() {
+        let port = MockPort::new();
+        port.state().max_write = 2;
+        reply_params(&port, &[(Parameter::LedDrive, "40")]);
+        let mut device = mock_device(&port);
+        assert_eq!(device.get_param(Parameter::LedDrive).unwrap(), "40");
+        assert_eq!(port.writes(), vec![b"GET,LED_DRIVE".to_vec()]);
+    }
+
+    #[test]
+    fn read_n_lines_reads_exactly_n() {
+        let port = MockPort::new();
+        port.reply(&[b"A,1\nB", b",2\nC,3\nD,4\n"]);
+        let mut device = mock_device(&port);
+        assert_eq!(device.read_n_lines(b"TABLE", 3).unwrap(), vec!["A,1", "B,2", "C,3"]);
+    }
+
+    #[test]
+    fn read_n_lines_returns_partial_lines_when_short() {
+        let port = MockPort::new();
+        port.reply(&[b"A,1\nB,"]);
+        let mut device = mock_device(&port);
+        match device.read_n_lines(b"TABLE", 3) {
+            Err(DeviceError::Incomplete { expected, lines }) => {
+                assert_eq!(expected, 3);
+                assert_eq!(lines, vec!["A,1"]);
+            }
+            other => panic!("expected Incomplete, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn builder_options_reach_the_device() {
+        let device = MyDevice::builder()
+            .baud(9600)
+            .timeout(Duration::from_millis(250))
+            .read_buffer_size(16)
+            .flush_before_command(false)
+            .with_port("MOCK", Box::new(MockPort::new()));
+        assert_eq!(device.settings.baud_rate, 9600);
+        assert_eq!(device.settings.timeout, Duration::from_millis(250));
+        assert_eq!(device.read_buffer_size, 16);
+        assert!(!device.flush_before_command);
+    }
+
+    #[test]
+    fn checksum_outgoing_appends_the_command_checksum() {
+        let port = MockPort::new();
+        port.reply(&[b"OK\n"]);
+        let mut device = MyDevice::builder()
+            .checksum_outgoing(true)
+            .with_port("MOCK", Box::new(port.clone()));
+        device.led_on().unwrap();
+        let expected = format!("LED,1;{:04X}", crc16_with_terminator("LED,1"));
+        assert_eq!(port.writes(), vec![expected.into_bytes()]);
+    }
+
+    #[test]
+    fn commands_have_no_checksum_by_default() {
+        let port = MockPort::new();
+        port.reply(&[b"OK\n"]);
+        let mut device = MyDevice::builder().with_port("MOCK", Box::new(port.clone()));
+        device.led_on().unwrap();
+        assert_eq!(port.writes(), vec![b"LED,1".to_vec()]);
+    }
+
+    #[test]
+    fn flow_control_defaults_to_none() {
+        assert_eq!(MyDeviceBuilder::new().settings.flow_control, FlowControl::None);
+        let builder = MyDevice::builder().flow_control(FlowControl::Hardware);
+        assert_eq!(builder.settings.flow_control, FlowControl::Hardware);
+    }
+
+    #[test]
+    fn transaction_runs_steps_in_order_and_stops_at_an_error() {
+        let port = MockPort::new();
+        //no reply to the second step, so it times out
+        port.reply(&[b"OK\n"]);
+        let mut device = mock_device(&port);
+        let res = device.transaction(|dev| {
+            dev.led_on()?;
+            dev.led_off()?;
+            dev.led_on()
+        });
+        assert!(res.is_err());
+        assert_eq!(port.writes(), vec![b"LED,1".to_vec(), b"LED,0".to_vec()]);
+    }
+
+    #[test]
+    fn send_command_tells_ack_from_data() {
+        let port = MockPort::new();
+        port.reply(&[b"OK\n"])
+            .reply(&[&data_line("GET,LED_DRIVE,0,40")])
+            .reply(&[b"GET,LED_DRIVE,0,40;0000\n"]);
+        let mut device = mock_device(&port);
+        assert_eq!(device.send_command(b"LED,1").unwrap(), Response::Ack(String::from("OK")));
+        let fields = ["GET", "LED_DRIVE", "0", "40"];
+        assert_eq!(
+            device.send_command(b"GET,LED_DRIVE").unwrap(),
+            Response::Data { fields: fields.iter().map(|f| f.to_string()).collect(), checksum_ok: true }
+        );
+        assert!(matches!(
+            device.send_command(b"GET,LED_DRIVE").unwrap(),
+            Response::Data { checksum_ok: false, .. }
+        ));
+    }
+
+    #[test]
+    fn led_on_sends_command_and_returns_ack() {
+        let port = MockPort::new();
+        port.reply(&[b"OK\n"]);
+        let mut device = mock_device(&port);
+        assert_eq!(device.led_on().unwrap(), "OK");
+        assert_eq!(port.writes(), vec![b"LED,1".to_vec()]);
+    }
+
+    #[test]
+    fn led_off_sends_command_and_returns_ack() {
+        let port = MockPort::new();
+        port.reply(&[b"OK\n"]);
+        let mut device = mock_device(&port);
+        assert_eq!(device.led_off().unwrap(), "OK");
+        assert_eq!(port.writes(), vec![b"LED,0".to_vec()]);
+    }
+
+    #[test]
+    fn get_param_returns_data_field() {
+        let port = MockPort::new();
+        port.reply(&[&data_line("GET,LED_DRIVE,0,40")]);
+        let mut device = mock_device(&port);
+        assert_eq!(device.get_param(Parameter::LedDrive).unwrap(), "40");
+        assert_eq!(port.writes(), vec![b"GET,LED_DRIVE".to_vec()]);
+    }
+
+    #[test]
+    fn data_call_surfaces_checksum_mismatch() {
+        let port = MockPort::new();
+        port.reply(&[b"GET,LED_DRIVE,0,40;0000\n"]);
+        let mut device = mock_device(&port);
+        match device.get_param(Parameter::LedDrive) {
+            Err(DeviceError::ChecksumMismatch { expected, received, raw }) => {
+                assert_eq!(expected, crc16_with_terminator("GET,LED_DRIVE,0,40"));
+                assert_eq!(received, "0000");
+                assert_eq!(raw, b"GET,LED_DRIVE,0,40;0000\n");
+            }
+            other => panic!("expected ChecksumMismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn data_call_fields_returns_every_field() {
+        let port = MockPort::new();
+        port.reply(&[&data_line("GET,LED_DRIVE,0,40,extra")]).reply(&[&data_line("GET,LED")]);
+        let mut device = mock_device(&port);
+        assert_eq!(
+            device.data_call_fields(b"GET,LED_DRIVE").unwrap(),
+            vec!["GET", "LED_DRIVE", "0", "40", "extra"]
+        );
+        //too few fields for a data value is an error rather than a panic
+        assert!(matches!(
+            device.get_param(Parameter::LedDrive),
+            Err(DeviceError::MalformedResponse { got: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn drop_turns_led_off_only_when_asked() {
+        let port = MockPort::new();
+        drop(mock_device(&port));
+        assert!(port.writes().is_empty());
+
+        port.reply(&[b"OK\n"]);
+        let mut device = mock_device(&port);
+        device.set_reset_on_drop(true);
+        drop(device);
+        assert_eq!(port.writes(), vec![b"LED,0".to_vec()]);
+    }
+
+    #[test]
+    fn expect_ack_reports_expected_and_received() {
+        let port = MockPort::new();
+        port.reply(&[b"OK\n"]).reply(&[b"BUSY\n"]);
+        let mut device = mock_device(&port);
+        assert_eq!(device.expect_ack(b"LED,1", "OK").unwrap(), "OK");
+        match device.expect_ack(b"LED,1", "OK") {
+            Err(DeviceError::UnexpectedAck { expected, got }) => {
+                assert_eq!(expected, "OK");
+                assert_eq!(got, "BUSY");
+            }
+            other => panic!("expected UnexpectedAck, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn execute_checks_acks_and_returns_data() {
+        let port = MockPort::new();
+        port.reply(&[b"OK\n"]).reply(&[&data_line("GET,LED_DRIVE,0,40")]).reply(&[b"NOPE\n"]);
+        let mut device = mock_device(&port);
+        assert_eq!(device.execute(Command::LedOn).unwrap(), "OK");
+        assert_eq!(device.execute(Command::GetParam(Parameter::LedDrive)).unwrap(), "40");
+        assert!(matches!(device.execute(Command::LedOff), Err(DeviceError::UnexpectedAck { .. })));
+        assert_eq!(port.writes(), vec![b"LED,1".to_vec(), b"GET,LED_DRIVE".to_vec(), b"LED,0".to_vec()]);
+    }
+
+    #[test]
+    fn raw_returns_unparsed_bytes() {
+        let port = MockPort::new();
+        port.reply(&[b"\xFF\x00no newline"]);
+        let mut device = mock_device(&port);
+        assert_eq!(device.raw(b"\x01\x02").unwrap(), b"\xFF\x00no newline");
+        assert_eq!(port.writes(), vec![b"\x01\x02".to_vec()]);
+    }
+
+    #[test]
+    fn dtr_and_rts_drive_the_control_lines() {
+        let port = MockPort::new();
+        let mut device = mock_device(&port);
+        device.set_dtr(true).unwrap();
+        device.set_rts(false).unwrap();
+        let state = port.state();
+        assert_eq!((state.dtr, state.rts), (Some(true), Some(false)));
+        drop(state);
+        device.set_dtr(false).unwrap();
+        assert_eq!(port.state().dtr, Some(false));
+    }
+
+    fn usb_port(port_name: &str, vid: u16, serial: &str) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: String::from(port_name),
+            port_type: SerialPortType::UsbPort(serialport::UsbPortInfo {
+                vid,
+                pid: MyDevice::PID,
+                serial_number: Some(String::from(serial)),
+                manufacturer: None,
+                product: None,
+            }),
+        }
+    }
+
+    #[test]
+    fn find_port_by_serial_needs_exactly_one_match() {
+        let ports = [
+            usb_port("COM3", MyDevice::VID, "A1"),
+            usb_port("COM4", 0x1234, "B2"), //another vendor's adapter
+            usb_port("COM5", MyDevice::VID, "C3"),
+            usb_port("COM6", MyDevice::VID, "C3"),
+        ];
+        assert_eq!(find_port_by_serial(&ports, "A1").unwrap(), "COM3");
+        assert!(matches!(find_port_by_serial(&ports, "B2"), Err(DeviceError::NotFound(_))));
+        match find_port_by_serial(&ports, "C3") {
+            Err(DeviceError::AmbiguousSerial { ports, .. }) => assert_eq!(ports, vec!["COM5", "COM6"]),
+            other => panic!("expected AmbiguousSerial, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn last_latency_covers_the_round_trip() {
+        let port = MockPort::new();
+        port.state().read_delay = Duration::from_millis(10);
+        port.reply(&[b"OK\n"]);
+        let mut device = mock_device(&port);
+        assert_eq!(device.last_latency(), None);
+        device.led_on().unwrap();
+        assert!(device.last_latency().unwrap() >= Duration::from_millis(10));
+    }
+
+    #[test]
+    fn cached_params_skip_the_port_until_invalidated() {
+        let port = MockPort::new();
+        reply_params(&port, &[(Parameter::LedDrive, "40"), (Parameter::LedDrive, "41")]);
+        port.reply(&[&data_line("SET,LED_DRIVE,0,50")]);
+        reply_params(&port, &[(Parameter::LedDrive, "50")]);
+        let mut device = MyDevice::builder().cache_params(true).with_port("MOCK", Box::new(port.clone()));
+
+        assert_eq!(device.get_param(Parameter::LedDrive).unwrap(), "40");
+        assert_eq!(device.get_param(Parameter::LedDrive).unwrap(), "40");
+        assert_eq!(port.writes().len(), 1);
+
+        device.invalidate_param(Parameter::LedDrive);
+        assert_eq!(device.get_param(Parameter::LedDrive).unwrap(), "41");
+
+        //a successful SET drops the stale value
+        device.set_param_typed(Parameter::LedDrive, ParamValue::Int(50)).unwrap();
+        assert_eq!(device.get_param(Parameter::LedDrive).unwrap(), "50");
+        assert_eq!(port.writes().len(), 4);
+    }
+
+    #[test]
+    fn json_export_imports_writable_params() {
+        let port = MockPort::new();
+        reply_params(&port, &ALL_PARAMS);
+        let json = mock_device(&port).export_params_json().unwrap();
+
+        let port = MockPort::new();
+        port.reply(&[&data_line("SET,CAL_SLOPE,0,1.05")]).reply(&[b"OK\n"]);
+        port.reply(&[&data_line("SET,LED_DRIVE,0,40")]).reply(&[b"OK\n"]);
+        let mut device = mock_device(&port);
+        device.import_params_json(&json).unwrap();
+        let writes = port.writes();
+        assert_eq!(writes.len(), 4);
+        assert_eq!(writes[0], b"SET,CAL_SLOPE,1.05");
+        assert_eq!(writes[2], b"SET,LED_DRIVE,40");
+
+        //one bad value and nothing is written
+        let port = MockPort::new();
+        let mut device = mock_device(&port);
+        let bad = r#"{"LED_DRIVE": "40", "CAL_SLOPE": "99"}"#;
+        assert!(matches!(device.import_params_json(bad), Err(DeviceError::InvalidParameter)));
+        assert!(port.writes().is_empty());
+    }
+
+    #[test]
+    fn line_ending_any_splits_on_every_ending_and_keeps_spaces() {
+        let mut lines = LineSplitter::new(LineEnding::Any);
+        let mut pending = b" a \r\nb\rc\n".to_vec();
+        let mut texts = Vec::new();
+        while let Some(frame) = lines.take_frame(&mut pending).unwrap() {
+            texts.push(frame.text);
+        }
+        assert_eq!(texts, vec![" a ", "b", "c"]);
+
+        //a "\r\n" split across reads is still one line ending
+        let mut pending = b"d\r".to_vec();
+        assert_eq!(lines.take_frame(&mut pending).unwrap().unwrap().text, "d");
+        pending.extend_from_slice(b"\ne\n");
+        assert_eq!(lines.take_frame(&mut pending).unwrap().unwrap().text, "e");
+
+        let mut lf = LineSplitter::new(LineEnding::Lf);
+        let mut pending = b" a \r\n".to_vec();
+        assert_eq!(lf.take_frame(&mut pending).unwrap().unwrap().text, "a");
+    }
+
+    #[test]
+    fn set_params_batch_saves_once_after_every_set() {
+        let port = MockPort::new();
+        port.reply(&[&data_line("SET,LED_DRIVE,0,40")]);
+        port.reply(&[&data_line("SET,LED_DRIVE,0,41")]);
+        port.reply(&[b"OK\n"]);
+        let mut device = mock_device(&port);
+        let values = [
+            (Parameter::LedDrive, String::from("40")),
+            (Parameter::LedDrive, String::from("41")),
+        ];
+        device.set_params_batch(&values).unwrap();
+        let writes = port.writes();
+        assert_eq!(writes.len(), 3);
+        assert_eq!(writes[..2], [b"SET,LED_DRIVE,40".to_vec(), b"SET,LED_DRIVE,41".to_vec()]);
+        assert_eq!(writes[2], b"CAL,1,1");
+
+        let port = MockPort::new();
+        let mut device = mock_device(&port);
+        let values = [
+            (Parameter::LedDrive, String::from("40")),
+            (Parameter::LedDrive, String::from("400")),
+        ];
+        assert!(matches!(device.set_params_batch(&values), Err(DeviceError::InvalidParameter)));
+        assert!(port.writes().is_empty());
+    }
+
+    #[test]
+    fn verify_settings_lists_every_mismatch() {
+        let port = MockPort::new();
+        let device = mock_device(&port);
+        assert!(device.verify_settings().is_ok());
+        //a driver that fell back to other settings
+        port.state().baud_rate = 9600;
+        port.state().flow_control = FlowControl::Hardware;
+        match device.verify_settings() {
+            Err(DeviceError::SettingsMismatch(mismatches)) => {
+                assert_eq!(mismatches.len(), 2);
+                assert!(mismatches[0].contains("9600"), "{}", mismatches[0]);
+                assert!(mismatches[1].starts_with("flow control"), "{}", mismatches[1]);
+            }
+            other => panic!("expected SettingsMismatch, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn send_break_asserts_and_clears_the_break() {
+        let port = MockPort::new();
+        let mut device = mock_device(&port);
+        device.send_break(Duration::from_millis(1)).unwrap();
+        {
+            let state = port.state();
+            assert_eq!(state.breaks, 1);
+            assert!(!state.in_break);
+        }
+
+        port.state().break_unsupported = true;
+        assert!(matches!(
+            device.send_break(Duration::from_millis(1)),
+            Err(DeviceError::BreakUnsupported(_))
+        ));
+    }
+
+    #[test]
+    fn flush_forwards_to_the_port() {
+        let port = MockPort::new();
+        let mut device = mock_device(&port);
+        let before = port.state().flushes;
+        device.flush().unwrap();
+        assert_eq!(port.state().flushes, before + 1);
+        assert!(port.writes().is_empty());
+        assert!(port.state().unflushed.is_empty());
+    }
+
+    #[test]
+    fn reply_without_line_ending_is_possibly_truncated() {
+        let port = MockPort::new();
+        port.reply(&[b"GET,LED_DRIVE,0,4"]);
+        let mut device = mock_device(&port);
+        match device.get_param(Parameter::LedDrive) {
+            Err(DeviceError::PossiblyTruncated(partial)) => assert_eq!(partial, "GET,LED_DRIVE,0,4"),
+            other => panic!("expected PossiblyTruncated, got {:?}", other),
+        }
+        //nothing at all is a plain timeout
+        assert!(matches!(device.led_on(), Err(DeviceError::Timeout)));
+    }
+
+    #[test]
+    fn trace_sees_sent_and_received_bytes() {
+        let port = MockPort::new();
+        port.reply(&[b"O", b"K\n"]);
+        let mut device = mock_device(&port);
+        let traced = std::sync::Arc::new(Mutex::new(Vec::new()));
+        let sink = traced.clone();
+        device.set_trace(Box::new(move |dir, bytes| sink.lock().unwrap().push((dir, bytes.to_vec()))));
+        device.led_on().unwrap();
+        let traced = traced.lock().unwrap().clone();
+        assert_eq!(traced[0], (Direction::Tx, b"LED,1".to_vec()));
+        let rx: Vec<u8> = traced[1..]
+            .iter()
+            .inspect(|(dir, _)| assert_eq!(*dir, Direction::Rx))
+            .flat_map(|(_, bytes)| bytes.clone())
+            .collect();
+        assert_eq!(rx, b"OK\n");
+    }
+
+    #[test]
+    fn parity_and_stop_bits_default_to_8n1() {
+        let settings = MyDeviceBuilder::new().settings;
+        assert_eq!((settings.parity, settings.stop_bits), (Parity::None, StopBits::One));
+
+        let port = MockPort::new();
+        let device = MyDevice::builder()
+            .parity(Parity::Even)
+            .stop_bits(StopBits::Two)
+            .with_port("MOCK", Box::new(port.clone()));
+        assert_eq!((device.settings.parity, device.settings.stop_bits), (Parity::Even, StopBits::Two));
+        assert!(matches!(device.verify_settings(), Err(DeviceError::SettingsMismatch(m)) if m.len() == 2));
+        port.state().parity = Parity::Even;
+        port.state().stop_bits = StopBits::Two;
+        assert!(device.verify_settings().is_ok());
+    }
+
+    #[test]
+    fn slow_trickle_stops_at_the_deadline() {
+        let port = MockPort::new();
+        port.state().read_delay = Duration::from_millis(20);
+        let chunks: Vec<&[u8]> = vec![b"x"; 20];
+        port.reply(&chunks);
+        let mut device = MyDevice::builder()
+            .timeout(Duration::from_millis(50))
+            .with_port("MOCK", Box::new(port.clone()));
+        port.state().timeout = Duration::from_millis(50);
+        assert!(matches!(device.led_on(), Err(DeviceError::PossiblyTruncated(_))));
+        let state = port.state();
+        assert!(state.reads < 10, "{} reads", state.reads);
+        assert_eq!(state.timeout, Duration::from_millis(50));
+    }
+
+    #[test]
+    fn get_param_as_parses_into_the_requested_type() {
+        let port = MockPort::new();
+        reply_params(&port, &[(Parameter::LedDrive, "40"), (Parameter::SerNumber, "A1B2C3D4E5F6"), (Parameter::LedDrive, "400")]);
+        let mut device = mock_device(&port);
+        assert_eq!(device.get_param_as::<u8>(Parameter::LedDrive).unwrap(), 40);
+        assert_eq!(device.get_param_as::<String>(Parameter::SerNumber).unwrap(), "A1B2C3D4E5F6");
+        //out of range for u8
+        assert!(matches!(device.get_param_as::<u8>(Parameter::LedDrive), Err(DeviceError::Parse(v)) if v == "400"));
+    }
+
+    #[test]
+    fn nth_field_is_bounds_checked() {
+        let fields = ["GET", "LED_DRIVE"];
+        assert_eq!(nth_field(&fields, 1).unwrap(), "LED_DRIVE");
+        match nth_field(&fields, 3) {
+            Err(DeviceError::MalformedResponse { expected_fields, got, .. }) => {
+                assert_eq!((expected_fields, got), (4, 2));
+            }
+            other => panic!("expected MalformedResponse, got {:?}", other),
+        }
+        let empty: [String; 0] = [];
+        assert!(nth_field(&empty, 0).is_err());
+    }
+
+    #[test]
+    fn parse_errors_carry_the_untrimmed_reply() {
+        let mut line = data_line("GET,LED");
+        line.insert(line.len() - 1, b'\r');
+        let port = MockPort::new();
+        port.reply(&[&line]);
+        let mut device = mock_device(&port);
+        let err = device.get_param(Parameter::LedDrive).unwrap_err();
+        assert!(matches!(err, DeviceError::MalformedResponse { .. }));
+        assert_eq!(err.raw(), Some(line.as_slice()));
+        assert_eq!(DeviceError::Timeout.raw(), None);
+    }
+
+    #[test]
+    fn send_no_reply_writes_without_reading() {
+        let port = MockPort::new();
+        port.state().read_delay = Duration::from_secs(5);
+        let mut device = mock_device(&port);
+        device.send_no_reply(b"RESET").unwrap();
+        assert_eq!(port.writes(), vec![b"RESET".to_vec()]);
+        assert_eq!(port.state().reads, 0);
+    }
+
+    #[test]
+    fn calibrate_timeout_scales_the_slowest_round_trip() {
+        let port = MockPort::new();
+        reply_params(&port, &[(Parameter::SerNumber, "A1B2C3D4E5F6"); 2]);
+        let mut device = mock_device(&port);
+        assert_eq!(device.calibrate_timeout(2).unwrap(), MIN_CALIBRATED_TIMEOUT);
+
+        port.state().read_delay = Duration::from_millis(40);
+        reply_params(&port, &[(Parameter::SerNumber, "A1B2C3D4E5F6"); 2]);
+        let timeout = device.calibrate_timeout(2).unwrap();
+        assert!(timeout >= Duration::from_millis(160), "{:?}", timeout);
+        assert_eq!(port.state().timeout, timeout);
+        assert_eq!(device.settings.timeout, timeout);
+
+        assert!(matches!(device.calibrate_timeout(0), Err(DeviceError::InvalidParameter)));
+    }
+
+    #[test]
+    fn read_only_params_are_rejected_before_writing() {
+        let port = MockPort::new();
+        let mut device = mock_device(&port);
+        assert!(matches!(
+            device.update_param(Parameter::SerNumber, String::from("A1B2C3D4E5F6")),
+            Err(DeviceError::ReadOnlyParameter(name)) if name == "SER_NUMBER"
+        ));
+        let values = [
+            (Parameter::LedDrive, String::from("40")),
+            (Parameter::FwVersion, String::from("1.2.3")),
+        ];
+        assert!(matches!(device.set_params_batch(&values), Err(DeviceError::ReadOnlyParameter(_))));
+        assert!(port.writes().is_empty());
+    }
+
+    #[test]
+    fn restore_reapplies_only_changed_params() {
+        let port = MockPort::new();
+        reply_params(&port, &ALL_PARAMS);
+        let mut device = mock_device(&port);
+        let snap = device.snapshot().unwrap();
+
+        //unchanged, only the dump is sent
+        reply_params(&port, &ALL_PARAMS);
+        device.restore(&snap).unwrap();
+        assert_eq!(port.writes().len(), 8);
+
+        let drifted = [
+            (Parameter::SerNumber, "A1B2C3D4E5F6"),
+            (Parameter::LedDrive, "90"),
+            (Parameter::FwVersion, "1.2.3"),
+            (Parameter::CalSlope, "1.05"),
+        ];
+        reply_params(&port, &drifted);
+        port.reply(&[&data_line("SET,LED_DRIVE,0,40")]).reply(&[b"OK\n"]);
+        device.restore(&snap).unwrap();
+        let writes = port.writes();
+        assert_eq!(writes.len(), 14);
+        assert_eq!(writes[12], b"SET,LED_DRIVE,40");
+        assert_eq!(writes[13], DEFAULT_SAVE_COMMAND.as_bytes());
+    }
+
+    #[test]
+    fn echoed_command_is_discarded_before_the_reply() {
+        let port = MockPort::new();
+        port.reply(&[b"LE", b"D,1OK\n"])
+            .reply(&[b"GET,LED_DRIVE", &data_line("GET,LED_DRIVE,0,40")])
+            .reply(&[b"OK\n"]);
+        let mut device = MyDevice::builder().echo(true).with_port("MOCK", Box::new(port.clone()));
+        assert_eq!(device.led_on().unwrap(), "OK");
+        assert_eq!(device.get_param(Parameter::LedDrive).unwrap(), "40");
+        //a reply without the echo is still read
+        assert_eq!(device.led_off().unwrap(), "OK");
+    }
+
+    #[test]
+    fn data_call_full_keeps_the_whole_record() {
+        let port = MockPort::new();
+        port.reply(&[&data_line("GET,LED_DRIVE,0,40")]).reply(&[b"GET,LED;0000\n"]);
+        let mut device = mock_device(&port);
+        let full = device.data_call_full(b"GET,LED_DRIVE").unwrap();
+        assert_eq!(full.fields, vec!["GET", "LED_DRIVE", "0", "40"]);
+        assert_eq!(full.value.as_deref(), Some("40"));
+        assert!(full.checksum_ok);
+
+        //short and corrupted, but still returned for inspection
+        let full = device.data_call_full(b"GET,LED").unwrap();
+        assert_eq!(full.fields, vec!["GET", "LED"]);
+        assert_eq!(full.value, None);
+        assert!(!full.checksum_ok);
+    }
+
+    #[test]
+    fn command_delay_pauses_before_each_command() {
+        assert_eq!(MyDeviceBuilder::new().command_delay, Duration::ZERO);
+        let port = MockPort::new();
+        port.reply(&[b"OK\n"]).reply(&[b"OK\n"]);
+        let mut device = MyDevice::builder()
+            .command_delay(Duration::from_millis(30))
+            .with_port("MOCK", Box::new(port.clone()));
+        let start = Instant::now();
+        device.led_on().unwrap();
+        device.led_off().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(60));
+    }
+
+    #[test]
+    fn modem_status_reads_every_input_line() {
+        let port = MockPort::new();
+        {
+            let mut state = port.state();
+            state.cts = true;
+            state.cd = true;
+        }
+        let mut device = mock_device(&port);
+        assert_eq!(
+            device.modem_status().unwrap(),
+            ModemStatus { cts: true, dsr: false, cd: true, ri: false }
+        );
+    }
+
+    #[test]
+    fn set_led_sets_drive_before_switching() {
+        let port = MockPort::new();
+        port.reply(&[&data_line("SET,LED_DRIVE,0,102")]).reply(&[b"OK\n"]).reply(&[b"OK\n"]);
+        let mut device = mock_device(&port);
+        device.set_led(true, Some(102)).unwrap();
+        device.set_led(false, None).unwrap();
+        let writes = port.writes();
+        assert_eq!(writes.len(), 3);
+        assert_eq!(writes[0], b"SET,LED_DRIVE,102");
+        assert_eq!(writes[1..], [b"LED,1".to_vec(), b"LED,0".to_vec()]);
+    }
+
+    #[test]
+    fn replay_sends_each_command_and_can_stop_on_error() {
+        let trace = vec![
+            (Direction::Tx, b"LED,1".to_vec()),
+            (Direction::Rx, b"OK\n".to_vec()),
+            (Direction::Tx, b"RESET".to_vec()),
+            (Direction::Tx, b"LED,0".to_vec()),
+        ];
+        let commands = commands_from_trace(&trace);
+        assert_eq!(commands, vec![b"LED,1".to_vec(), b"RESET".to_vec(), b"LED,0".to_vec()]);
+
+        //RESET gets no reply
+        let port = MockPort::new();
+        port.reply(&[b"OK\n"]).reply(&[]).reply(&[b"OK\n"]);
+        let results = mock_device(&port).replay(&commands, false);
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap(), "OK\n");
+        assert!(results[1].is_err());
+        assert_eq!(results[2].as_ref().unwrap(), "OK\n");
+
+        let port = MockPort::new();
+        port.reply(&[b"OK\n"]).reply(&[]);
+        assert_eq!(mock_device(&port).replay(&commands, true).len(), 2);
+        assert_eq!(port.writes().len(), 2);
     }
 }
@@ -0,0 +1,111 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::my_device::MyDevice;
+use crate::parameters::Parameter;
+
+///Serves `device` to TCP clients at `addr`, one thread per connection.
+///
+///Clients send one command per line and get one reply line back:
+///`LED ON`, `LED OFF`, `GET <param>` and `SET <param> <value>` (set and saved),
+///answered with `OK <reply>` or `ERR <reason>`. Only returns if the listener fails.
+pub fn serve(device: Arc<Mutex<MyDevice>>, addr: SocketAddr) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Failed to accept connection: {}", e);
+                continue;
+            }
+        };
+        let device = device.clone();
+        thread::spawn(move || {
+            if let Err(e) = handle_client(&device, stream) {
+                eprintln!("Client connection failed: {}", e);
+            }
+        });
+    }
+    Ok(())
+}
+
+//Answers each line from the client until it disconnects
+fn handle_client(device: &Mutex<MyDevice>, stream: TcpStream) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        let reply = match dispatch(device, line.trim()) {
+            Ok(reply) => format!("OK {}", reply),
+            Err(e) => format!("ERR {}", e),
+        };
+        writeln!(writer, "{}", reply)?;
+    }
+    Ok(())
+}
+
+//Maps a text command onto the MyDevice method that implements it
+fn dispatch(device: &Mutex<MyDevice>, line: &str) -> Result<String, String> {
+    let mut words = line.splitn(3, ' ');
+    let command = words.next().unwrap_or_default().to_ascii_uppercase();
+    let mut device = device.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let reply = match (command.as_str(), words.next(), words.next()) {
+        ("LED", Some(state), None) if state.eq_ignore_ascii_case("on") => device.led_on(),
+        ("LED", Some(state), None) if state.eq_ignore_ascii_case("off") => device.led_off(),
+        ("GET", Some(param), None) => device.get_param(parse_param(param)?),
+        ("SET", Some(param), Some(value)) => {
+            device.update_param(parse_param(param)?, String::from(value))
+        }
+        _ => return Err(format!("Unknown command: {:?}", line)),
+    };
+    reply.map_err(|e| e.to_string())
+}
+
+fn parse_param(name: &str) -> Result<Parameter, String> {
+    name.parse::<Parameter>().map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_port::{data_line, MockPort};
+
+    fn mock_device(port: &MockPort) -> Mutex<MyDevice> {
+        Mutex::new(MyDevice::from_port("MOCK", port.clone()))
+    }
+
+    #[test]
+    fn dispatch_maps_text_commands_to_device_calls() {
+        let port = MockPort::new();
+        port.reply(&[b"OK\n"]).reply(&[&data_line("GET,LED_DRIVE,0,40")]);
+        let device = mock_device(&port);
+        assert_eq!(dispatch(&device, "led on"), Ok(String::from("OK")));
+        assert_eq!(dispatch(&device, "GET LED_DRIVE"), Ok(String::from("40")));
+        assert!(dispatch(&device, "GET NOT_A_PARAM").is_err());
+        assert!(dispatch(&device, "BLINK").is_err());
+        assert_eq!(port.writes(), vec![b"LED,1".to_vec(), b"GET,LED_DRIVE".to_vec()]);
+    }
+
+    #[test]
+    fn client_gets_one_reply_line_per_command() {
+        let port = MockPort::new();
+        port.reply(&[b"OK\n"]);
+        let device = Arc::new(mock_device(&port));
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            handle_client(&device, stream).unwrap();
+        });
+
+        let mut client = TcpStream::connect(addr).unwrap();
+        client.write_all(b"LED OFF\nBLINK\n").unwrap();
+        client.shutdown(std::net::Shutdown::Write).unwrap();
+        let replies: Vec<String> = BufReader::new(client).lines().map(Result::unwrap).collect();
+        server.join().unwrap();
+        assert_eq!(replies[0], "OK OK");
+        assert!(replies[1].starts_with("ERR "), "{}", replies[1]);
+        assert_eq!(replies.len(), 2);
+    }
+}
@@ -1,45 +1,395 @@
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
 use regex::Regex;
 
+//Compiled once on first use rather than per is_valid call
+fn ser_number_regex() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new("^[0-9a-zA-Z]{12}$").unwrap())
+}
+
+//Dotted numeric version i.e. 1.2.3
+fn fw_version_regex() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(r"^[0-9]+(\.[0-9]+)+$").unwrap())
+}
 
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Parameter {
     SerNumber,
     LedDrive,
+    FwVersion,
+    CalSlope,
+}
+
+///Calibration slopes accepted unless a device is given its own range,
+///see `MyDeviceBuilder::cal_slope_range`
+pub const DEFAULT_CAL_SLOPE_RANGE: (f32, f32) = (-10.0, 10.0);
+
+///Kind of value a parameter holds on the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParamType {
+    Int,
+    Float,
+    Text,
+}
+
+///Whether a parameter can be read from and written to the device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    ReadOnly,
+    WriteOnly,
+    ReadWrite,
+}
+
+impl Access {
+    pub fn is_readable(&self) -> bool {
+        matches!(self, Access::ReadOnly | Access::ReadWrite)
+    }
+
+    pub fn is_writable(&self) -> bool {
+        matches!(self, Access::WriteOnly | Access::ReadWrite)
+    }
+}
+
+///Typed parameter value
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParamValue {
+    Int(i32),
+    Float(f32),
+    Text(String),
+}
+
+impl ParamValue {
+    pub fn param_type(&self) -> ParamType {
+        match &self {
+            ParamValue::Int(_) => ParamType::Int,
+            ParamValue::Float(_) => ParamType::Float,
+            ParamValue::Text(_) => ParamType::Text,
+        }
+    }
+}
+
+impl fmt::Display for ParamValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self {
+            ParamValue::Int(val) => write!(f, "{}", val),
+            ParamValue::Float(val) => write!(f, "{}", val),
+            ParamValue::Text(val) => write!(f, "{}", val),
+        }
+    }
 }
 
 impl Parameter {
+    pub const SER_NUMBER: &'static str = "SER_NUMBER";
+    pub const LED_DRIVE: &'static str = "LED_DRIVE";
+    pub const FW_VERSION: &'static str = "FW_VERSION";
+    pub const CAL_SLOPE: &'static str = "CAL_SLOPE";
 
-    pub fn as_str(&self) -> &str {
+    ///every parameter the device supports
+    pub fn all() -> &'static [Parameter] {
+        &[
+            Parameter::SerNumber,
+            Parameter::LedDrive,
+            Parameter::FwVersion,
+            Parameter::CalSlope,
+        ]
+    }
+
+    ///name the firmware knows the parameter by, every other spelling goes through this
+    pub fn wire_name(&self) -> &'static str {
         match &self{
-            Parameter::SerNumber => "SER_NUMBER",
-            Parameter::LedDrive => "LED_DRIVE",
+            Parameter::SerNumber => Parameter::SER_NUMBER,
+            Parameter::LedDrive => Parameter::LED_DRIVE,
+            Parameter::FwVersion => Parameter::FW_VERSION,
+            Parameter::CalSlope => Parameter::CAL_SLOPE,
         }
     }
 
+    pub fn as_str(&self) -> &str {
+        self.wire_name()
+    }
+
     pub fn as_string(&self) -> String {
+        String::from(self.wire_name())
+    }
+    
+    ///numeric code from the firmware's parameter table, None where the table has none
+    pub fn as_int(&self) -> Option<u8> {
         match &self{
-            Parameter::SerNumber => String::from("SER_NUMBER"),
-            Parameter::LedDrive => String::from("LED_DRIVE"),
+            Parameter::SerNumber => Some(0),
+            Parameter::LedDrive => Some(9),
+            Parameter::FwVersion => None,
+            Parameter::CalSlope => None,
         }
     }
-    
-    pub fn as_int(&self) -> u8 {
+
+    ///maps a wire code from `as_int` back to its parameter
+    pub fn from_int(code: u8) -> Option<Parameter> {
+        Parameter::all()
+            .iter()
+            .find(|param| param.as_int() == Some(code))
+            .copied()
+    }
+
+    ///serial number and firmware version are set at the factory
+    pub fn access(&self) -> Access {
+        match &self{
+            Parameter::SerNumber | Parameter::FwVersion => Access::ReadOnly,
+            Parameter::LedDrive | Parameter::CalSlope => Access::ReadWrite,
+        }
+    }
+
+    pub fn value_type(&self) -> ParamType {
+        match &self{
+            Parameter::SerNumber => ParamType::Text,
+            Parameter::LedDrive => ParamType::Int,
+            Parameter::FwVersion => ParamType::Text,
+            Parameter::CalSlope => ParamType::Float,
+        }
+    }
+
+    ///smallest accepted value, None for text and float parameters
+    pub fn min(&self) -> Option<i32> {
+        match &self{
+            Parameter::SerNumber | Parameter::FwVersion | Parameter::CalSlope => None,
+            Parameter::LedDrive => Some(0),
+        }
+    }
+
+    ///largest accepted value, None for text and float parameters
+    pub fn max(&self) -> Option<i32> {
+        match &self{
+            Parameter::SerNumber | Parameter::FwVersion | Parameter::CalSlope => None,
+            Parameter::LedDrive => Some(255),
+        }
+    }
+
+    ///default accepted (min, max) of a float parameter, None for the others
+    pub fn float_range(&self) -> Option<(f32, f32)> {
+        match &self{
+            Parameter::CalSlope => Some(DEFAULT_CAL_SLOPE_RANGE),
+            _ => None,
+        }
+    }
+
+    ///value to offer before anything has been read from the device
+    pub fn default(&self) -> ParamValue {
         match &self{
-            Parameter::SerNumber => 0,
-            Parameter::LedDrive => 9,
+            Parameter::SerNumber => ParamValue::Text(String::new()),
+            Parameter::LedDrive => ParamValue::Int(0),
+            Parameter::FwVersion => ParamValue::Text(String::new()),
+            Parameter::CalSlope => ParamValue::Float(1.0),
+        }
+    }
+
+    ///parses a raw device value into the parameter's typed value
+    pub fn parse_value(&self, value: &str) -> Option<ParamValue> {
+        let value = value.trim();
+        match self.value_type() {
+            ParamType::Int => value.parse::<i32>().ok().map(ParamValue::Int),
+            ParamType::Float => value
+                .parse::<f32>()
+                .ok()
+                .filter(|val| val.is_finite())
+                .map(ParamValue::Float),
+            ParamType::Text => Some(ParamValue::Text(String::from(value))),
         }
     }
 
     ///validates a value for a given parameter value
     pub fn is_valid(&self, value: &str) -> bool {
+        self.is_valid_within(value, self.float_range())
+    }
+
+    ///is_valid with `float_range` in place of the default range of a float parameter
+    pub fn is_valid_within(&self, value: &str, float_range: Option<(f32, f32)>) -> bool {
         match &self{
             Parameter::SerNumber => {
-                let re = Regex::new("[0-9a-zA-Z]{12}").unwrap();
-                re.is_match(value)
+                ser_number_regex().is_match(value)
             },
             Parameter::LedDrive => {
-                let val = value.parse::<i32>().unwrap();
-                0 <= val && val <= 255
+                if value.trim() != value {
+                    return false;
+                }
+                let min = self.min().unwrap_or(i32::MIN);
+                let max = self.max().unwrap_or(i32::MAX);
+                match value.parse::<i32>() {
+                    Ok(val) => (min..=max).contains(&val),
+                    Err(_) => false,
+                }
             },
+            Parameter::FwVersion => {
+                fw_version_regex().is_match(value)
+            },
+            Parameter::CalSlope => {
+                if value.trim() != value {
+                    return false;
+                }
+                let (min, max) = float_range.unwrap_or((f32::MIN, f32::MAX));
+                //f32 parsing accepts "NaN" and "inf", neither means anything as a slope
+                match value.parse::<f32>() {
+                    Ok(val) => val.is_finite() && (min..=max).contains(&val),
+                    Err(_) => false,
+                }
+            },
+        }
+    }
+}
+
+///Returned when a string isn't the wire name of any parameter
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownParameter(pub String);
+
+impl fmt::Display for UnknownParameter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Unknown parameter: {:?}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownParameter {}
+
+impl FromStr for Parameter {
+    type Err = UnknownParameter;
+
+    ///maps a wire name like "LED_DRIVE" back to its parameter
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Parameter::all()
+            .iter()
+            .find(|param| param.wire_name() == s)
+            .copied()
+            .ok_or_else(|| UnknownParameter(String::from(s)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_value_follows_value_type() {
+        assert_eq!(Parameter::LedDrive.parse_value(" 40 "), Some(ParamValue::Int(40)));
+        assert_eq!(Parameter::LedDrive.parse_value("forty"), None);
+        assert_eq!(
+            Parameter::SerNumber.parse_value("A1B2C3D4E5F6"),
+            Some(ParamValue::Text(String::from("A1B2C3D4E5F6")))
+        );
+        assert_eq!(ParamValue::Int(40).param_type(), ParamType::Int);
+        assert_eq!(ParamValue::Int(40).to_string(), "40");
+    }
+
+    #[test]
+    fn led_drive_rejects_non_numeric_and_out_of_range() {
+        for value in ["0", "40", "255"] {
+            assert!(Parameter::LedDrive.is_valid(value), "{}", value);
         }
+        for value in ["", "abc", "4O", "-1", "256", " 40", "40\n", "99999999999"] {
+            assert!(!Parameter::LedDrive.is_valid(value), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn ser_number_must_be_exactly_twelve_alphanumerics() {
+        assert!(Parameter::SerNumber.is_valid("A1B2C3D4E5F6"));
+        for value in ["A1B2C3D4E5F", "A1B2C3D4E5F6G", "xA1B2C3D4E5F6", "A1B2C3D4E5F6\n", "A1B2C3-4E5F6", ""] {
+            assert!(!Parameter::SerNumber.is_valid(value), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn wire_names_are_unique_and_round_trip() {
+        let names: std::collections::BTreeSet<&str> =
+            Parameter::all().iter().map(|param| param.as_str()).collect();
+        assert_eq!(names.len(), Parameter::all().len());
+        for param in Parameter::all() {
+            assert_eq!(param.as_str().parse::<Parameter>(), Ok(*param));
+        }
+        assert_eq!(
+            "led_drive".parse::<Parameter>(),
+            Err(UnknownParameter(String::from("led_drive")))
+        );
+    }
+
+    #[test]
+    fn metadata_matches_value_type() {
+        assert_eq!((Parameter::LedDrive.min(), Parameter::LedDrive.max()), (Some(0), Some(255)));
+        for param in [Parameter::SerNumber, Parameter::FwVersion, Parameter::CalSlope] {
+            assert_eq!((param.min(), param.max()), (None, None));
+        }
+        for param in Parameter::all() {
+            assert_eq!(param.default().param_type(), param.value_type());
+        }
+    }
+
+    #[test]
+    fn from_int_only_decodes_assigned_codes() {
+        let decoded: Vec<(u8, Parameter)> = (0..=u8::MAX)
+            .filter_map(|code| Parameter::from_int(code).map(|param| (code, param)))
+            .collect();
+        assert_eq!(decoded, vec![(0, Parameter::SerNumber), (9, Parameter::LedDrive)]);
+        for (code, param) in decoded {
+            assert_eq!(param.as_int(), Some(code));
+        }
+    }
+
+    #[test]
+    fn access_flags() {
+        assert!(Access::ReadOnly.is_readable() && !Access::ReadOnly.is_writable());
+        assert!(!Access::WriteOnly.is_readable() && Access::WriteOnly.is_writable());
+        assert!(Access::ReadWrite.is_readable() && Access::ReadWrite.is_writable());
+        assert_eq!(Parameter::SerNumber.access(), Access::ReadOnly);
+    }
+
+    #[test]
+    fn name_accessors_agree_with_the_consts() {
+        let consts = [
+            (Parameter::SerNumber, Parameter::SER_NUMBER),
+            (Parameter::LedDrive, Parameter::LED_DRIVE),
+            (Parameter::FwVersion, Parameter::FW_VERSION),
+            (Parameter::CalSlope, Parameter::CAL_SLOPE),
+        ];
+        assert_eq!(consts.len(), Parameter::all().len());
+        for (param, name) in consts {
+            assert_eq!(param.wire_name(), name);
+            assert_eq!(param.as_str(), name);
+            assert_eq!(param.as_string(), name);
+        }
+    }
+
+    #[test]
+    fn int_codes_round_trip() {
+        for param in Parameter::all() {
+            if let Some(code) = param.as_int() {
+                assert_eq!(Parameter::from_int(code), Some(*param));
+            }
+        }
+        assert_eq!(Parameter::FwVersion.as_int(), None);
+        assert_eq!(Parameter::from_int(1), None);
+    }
+
+    #[test]
+    fn cal_slope_accepts_finite_values_in_range() {
+        for value in ["1.05", "-0.5", "0", "10", "-10.0"] {
+            assert!(Parameter::CalSlope.is_valid(value), "{}", value);
+        }
+        for value in ["NaN", "inf", "-inf", "10.5", "-11", " 1.0", "", "slope"] {
+            assert!(!Parameter::CalSlope.is_valid(value), "{}", value);
+        }
+    }
+
+    #[test]
+    fn cal_slope_range_can_be_widened() {
+        assert!(Parameter::CalSlope.is_valid_within("15", Some((0.0, 20.0))));
+        assert!(!Parameter::CalSlope.is_valid_within("-1", Some((0.0, 20.0))));
+        assert!(!Parameter::CalSlope.is_valid_within("NaN", Some((f32::MIN, f32::MAX))));
+    }
+
+    #[test]
+    fn cal_slope_parses_as_float() {
+        assert_eq!(Parameter::CalSlope.parse_value("1.050"), Some(ParamValue::Float(1.05)));
+        assert_eq!(Parameter::CalSlope.parse_value("NaN"), None);
     }
 }
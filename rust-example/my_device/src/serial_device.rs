@@ -1,8 +1,23 @@
 use std::sync::{Mutex};
 
+use crate::error::DeviceError;
+
 pub trait SerialDevice: Send {
     type Device: Send;
-    fn open(path: &str) -> Mutex<Self::Device>;
+    ///Open the device at the given OS path, returning an error if it can't be claimed.
+    fn open(path: &str) -> Result<Mutex<Self::Device>, DeviceError>;
     const VID: u16;
     const PID: u16;
+    const BAUD_RATE: u32;
+
+    ///All (VID, PID) pairs that identify this device, defaults to just `VID`/`PID`.
+    fn usb_ids() -> &'static [(u16, u16)] {
+        &[(Self::VID, Self::PID)]
+    }
+
+    ///Serial number read from a freshly opened device, None if it doesn't report one.
+    ///Lets a manager tell apart identical units sharing a VID/PID.
+    fn read_serial(_device: &mut Self::Device) -> Option<String> {
+        None
+    }
 }